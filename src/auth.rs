@@ -0,0 +1,338 @@
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+use crate::config::{check_profile_name, DEFAULT_PROFILE};
+use crate::utils::write_private_file;
+use crate::yunpan_service::YunPanError;
+
+/**
+ * 百度OAuth 设备码模式(device code)登录 doc: https://pan.baidu.com/union/doc/fl1x114ti
+ * 1. 获取设备码和用户码, 用户在浏览器打开verification_url输入用户码授权
+ * 2. 按interval轮询token接口, 直到用户完成授权或者设备码过期
+ */
+pub struct BaiduOAuth {
+    app_key: String,
+    app_secret: String,
+    client: Client,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceCodeResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_url: String,
+    pub qrcode_url: Option<String>,
+    pub expires_in: u64, //设备码有效期(秒)
+    pub interval: u64, //轮询间隔(秒)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OAuthTokenResponse {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>, //access_token有效期(秒) 一般为30天
+    scope: Option<String>,
+    error: Option<String>, //authorization_pending/slow_down/expired_token/...
+    error_description: Option<String>,
+}
+
+/**
+ * 持久化的token, 过期时间为绝对时间(秒)
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_at: u64,
+    pub scope: Option<String>,
+}
+
+impl OAuthToken {
+    /** secs秒内是否会过期 */
+    pub fn expires_within(&self, secs: u64) -> bool {
+        now_secs() + secs >= self.expires_at
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl BaiduOAuth {
+    pub fn new(app_key: &str, app_secret: &str) -> Self {
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("Failed to build HTTP client");
+
+        BaiduOAuth {
+            app_key: app_key.to_string(),
+            app_secret: app_secret.to_string(),
+            client,
+        }
+    }
+
+    pub async fn device_code(&self) -> Result<DeviceCodeResponse, YunPanError> {
+        let mut url = Url::parse("https://openapi.baidu.com/oauth/2.0/device/code").unwrap();
+        url.query_pairs_mut()
+            .append_pair("response_type", "device_code")
+            .append_pair("client_id", &self.app_key)
+            .append_pair("scope", "basic,netdisk");
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = response.text().await?;
+
+        serde_json::from_str::<DeviceCodeResponse>(&raw_response_text).map_err(|e| {
+            log::error!("serde_json::from_str failed on device code response: {:?}", raw_response_text);
+            YunPanError::Biz(format!("get device code failed: {} {:?}", e, raw_response_text))
+        })
+    }
+
+    async fn request_token(&self, params: &[(&str, &str)]) -> Result<OAuthTokenResponse, YunPanError> {
+        let mut url = Url::parse("https://openapi.baidu.com/oauth/2.0/token").unwrap();
+        url.query_pairs_mut()
+            .extend_pairs(params)
+            .append_pair("client_id", &self.app_key)
+            .append_pair("client_secret", &self.app_secret);
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = response.text().await?;
+
+        serde_json::from_str::<OAuthTokenResponse>(&raw_response_text).map_err(|e| {
+            log::error!("serde_json::from_str failed on token response: {:?}", raw_response_text);
+            YunPanError::Serde(e)
+        })
+    }
+
+    /**
+     * 轮询直到用户完成授权
+     */
+    pub async fn poll_token(&self, device: &DeviceCodeResponse) -> Result<OAuthToken, YunPanError> {
+        let deadline = now_secs() + device.expires_in;
+        let mut interval = device.interval.max(1);
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            if now_secs() > deadline {
+                return Err(YunPanError::Biz("device code expired before authorization".to_string()));
+            }
+
+            let response = self.request_token(&[
+                ("grant_type", "device_token"),
+                ("code", &device.device_code),
+            ]).await?;
+            match response.error.as_deref() {
+                None => return into_token(response),
+                Some("authorization_pending") => log::debug!("waiting for authorization..."),
+                Some("slow_down") => interval += 5,
+                Some(error) => {
+                    return Err(YunPanError::Biz(format!("authorization failed: {} {}",
+                        error, response.error_description.unwrap_or_default())));
+                }
+            }
+        }
+    }
+
+    /**
+     * 用refresh_token换新的access_token, 注意refresh_token也会一起更新(旧的只能用一次)
+     */
+    pub async fn refresh_token(&self, refresh_token: &str) -> Result<OAuthToken, YunPanError> {
+        let response = self.request_token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ]).await?;
+        match response.error.as_deref() {
+            None => into_token(response),
+            Some(error) => Err(YunPanError::AuthExpired(format!("refresh token failed: {} {}, please run login again",
+                error, response.error_description.unwrap_or_default()))),
+        }
+    }
+}
+
+fn into_token(response: OAuthTokenResponse) -> Result<OAuthToken, YunPanError> {
+    match (response.access_token, response.refresh_token) {
+        (Some(access_token), Some(refresh_token)) => Ok(OAuthToken {
+            access_token,
+            refresh_token,
+            expires_at: now_secs() + response.expires_in.unwrap_or(0),
+            scope: response.scope,
+        }),
+        _ => Err(YunPanError::Biz("token response without access_token/refresh_token".to_string())),
+    }
+}
+
+/**
+ * 登录后保存的凭证 (刷新token时需要app_key/app_secret, 所以一起保存)
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub app_key: String,
+    pub app_secret: String,
+    pub token: OAuthToken,
+}
+
+/**
+ * 凭证文件 每个profile一个: <配置目录>/baidu_yunpan/tokens/<profile>.json, 只有当前用户可读写
+ */
+pub struct TokenStore {
+    path: PathBuf,
+}
+
+impl TokenStore {
+    pub fn for_profile(profile: &str) -> Option<TokenStore> {
+        //不合法的profile名可能跳出tokens目录
+        check_profile_name(profile).ok()?;
+        let dir = dirs::config_dir()?.join("baidu_yunpan");
+        let path = dir.join("tokens").join(format!("{}.json", profile));
+        //兼容没有profile之前login保存的 token.json, 当作default profile的凭证
+        let legacy_path = dir.join("token.json");
+        if profile == DEFAULT_PROFILE && !path.exists() && legacy_path.exists() {
+            return Some(TokenStore { path: legacy_path });
+        }
+        Some(TokenStore { path })
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn remove(&self) -> Result<(), std::io::Error> {
+        if self.path.exists() {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn load(&self) -> Option<Credentials> {
+        let content = std::fs::read_to_string(&self.path).ok()?;
+        match serde_json::from_str::<Credentials>(&content) {
+            Ok(credentials) => Some(credentials),
+            Err(e) => {
+                log::warn!("ignore broken token file {:?}: {}", self.path, e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, credentials: &Credentials) -> Result<(), std::io::Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let content = serde_json::to_string_pretty(credentials).map_err(std::io::Error::other)?;
+        write_private_file(&self.path, &self.path.with_extension("json.tmp"), content.as_bytes())
+    }
+}
+
+/** 距离过期不到这个时间(秒)时提前刷新 */
+const REFRESH_AHEAD_SECS: u64 = 24 * 3600;
+
+/** 提前刷新失败后, 隔多久再尝试 (token还没过期, 不用每个请求都去刷新) */
+const REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/**
+ * 提供access_token: 命令行/环境变量指定的token是固定的,
+ * login保存的凭证可以在快过期时(或者接口返回token过期时)用refresh_token刷新并写回凭证文件
+ */
+pub struct TokenProvider {
+    state: tokio::sync::Mutex<TokenState>,
+}
+
+struct TokenState {
+    access_token: String,
+    refreshable: Option<(Credentials, TokenStore)>,
+    refresh_failed_at: Option<std::time::Instant>,//上次提前刷新失败的时间
+}
+
+impl TokenProvider {
+    pub fn fixed(access_token: String) -> Self {
+        TokenProvider {
+            state: tokio::sync::Mutex::new(TokenState { access_token, refreshable: None, refresh_failed_at: None }),
+        }
+    }
+
+    pub fn refreshable(credentials: Credentials, store: TokenStore) -> Self {
+        TokenProvider {
+            state: tokio::sync::Mutex::new(TokenState {
+                access_token: credentials.token.access_token.clone(),
+                refreshable: Some((credentials, store)),
+                refresh_failed_at: None,
+            }),
+        }
+    }
+
+    /**
+     * 当前的access_token, 快过期时先刷新
+     * 刷新失败(比如网络抖动)时如果token还没有真正过期, 继续使用当前的token, 过一段时间再尝试刷新
+     */
+    pub async fn access_token(&self) -> Result<String, YunPanError> {
+        let mut state = self.state.lock().await;
+        let (expiring, expired) = match &state.refreshable {
+            Some((credentials, _)) => (credentials.token.expires_within(REFRESH_AHEAD_SECS), credentials.token.expires_within(0)),
+            None => (false, false),
+        };
+        let backing_off = state.refresh_failed_at.is_some_and(|at| at.elapsed() < REFRESH_RETRY_INTERVAL);
+        if expired || (expiring && !backing_off) {
+            log::info!("access_token is about to expire, refreshing");
+            match state.refresh().await {
+                Ok(()) => state.refresh_failed_at = None,
+                Err(e) if !expired => {
+                    log::warn!("failed to refresh access_token, keep using the current one: {}", e);
+                    state.refresh_failed_at = Some(std::time::Instant::now());
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(state.access_token.clone())
+    }
+
+    /**
+     * 接口返回token过期时调用, stale为调用时使用的token
+     * 已经被其他请求刷新过时不再重复刷新; 固定的token无法刷新时返回false
+     */
+    pub async fn refresh(&self, stale: &str) -> Result<bool, YunPanError> {
+        let mut state = self.state.lock().await;
+        if state.access_token != stale {
+            return Ok(true);
+        }
+        if state.refreshable.is_none() {
+            return Ok(false);
+        }
+        log::info!("access_token has expired, refreshing");
+        state.refresh().await?;
+        Ok(true)
+    }
+}
+
+impl TokenState {
+    async fn refresh(&mut self) -> Result<(), YunPanError> {
+        let (credentials, store) = match &mut self.refreshable {
+            Some(refreshable) => refreshable,
+            None => return Ok(()),
+        };
+        let oauth = BaiduOAuth::new(&credentials.app_key, &credentials.app_secret);
+        credentials.token = oauth.refresh_token(&credentials.token.refresh_token).await?;
+        //新的refresh_token必须保存, 否则下次就刷新不了了
+        store.save(credentials)?;
+        self.access_token = credentials.token.access_token.clone();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CliLoginResponse {
+    pub token_path: PathBuf,
+    pub expires_at: u64,
+    pub scope: Option<String>,
+}
+impl std::fmt::Display for CliLoginResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let days = self.expires_at.saturating_sub(now_secs()) / 86400;
+        write!(f, "Login successful, token saved to {} (expires in {} days)", self.token_path.display(), days)
+    }
+}
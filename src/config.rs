@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use crate::utils::write_private_file;
+use crate::yunpan_service::{ConflictPolicy, YunPanError};
+
+/** 没有指定profile并且配置文件中也没有default_profile时使用的profile名 */
+pub const DEFAULT_PROFILE: &str = "default";
+
+/**
+ * profile名只能包含字母、数字、_ 和 - (会用作凭证的文件名, 不能包含路径分隔符和..)
+ */
+pub fn check_profile_name(name: &str) -> Result<(), YunPanError> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        Ok(())
+    } else {
+        Err(YunPanError::InvalidArgument(format!("invalid profile name {:?}, only letters, digits, _ and - are allowed", name)))
+    }
+}
+
+/**
+ * 配置文件 默认为 <配置目录>/baidu_yunpan/config.toml (linux下为 ~/.config/baidu_yunpan/config.toml)
+ *
+ * default_profile = "personal"
+ *
+ * [profiles.personal]
+ * app_key = "..."
+ * app_secret = "..."
+ * app_root = "/apps/asitanokibou"
+ * chunk_size = 16        # MB
+ * parallel = 4
+ * on_conflict = "rename"
+ * proxy = "http://127.0.0.1:7890"
+ * upload_limit = 2048    # KB/s
+ * download_limit = 4096  # KB/s
+ * max_attempts = 5
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/**
+ * 一个百度账号的配置, 没有配置的项使用命令行参数或者默认值
+ */
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub access_token: Option<String>, //固定的access_token, 一般用login保存的token即可
+    pub app_key: Option<String>,
+    pub app_secret: Option<String>,
+    pub app_root: Option<String>, //应用目录 相对的远程路径都基于这个目录
+    pub chunk_size: Option<u64>, //分片大小 (MB)
+    pub parallel: Option<usize>, //同时上传/下载的分片数
+    pub on_conflict: Option<ConflictPolicy>,
+    pub proxy: Option<String>, //http(s)代理 如 http://127.0.0.1:7890
+    pub upload_limit: Option<u64>, //上传限速 (KB/s)
+    pub download_limit: Option<u64>, //下载限速 (KB/s)
+    pub max_attempts: Option<u32>, //临时性错误最多尝试的次数
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("baidu_yunpan").join("config.toml"))
+    }
+
+    /**
+     * 读取配置文件, 文件不存在时返回空配置
+     */
+    pub fn load(path: &Path) -> Result<Config, YunPanError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(YunPanError::Io(e)),
+        };
+        toml::from_str::<Config>(&content)
+            .map_err(|e| YunPanError::Biz(format!("invalid config file {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), YunPanError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let content = toml::to_string_pretty(self)
+            .map_err(|e| YunPanError::Biz(format!("serialize config failed: {}", e)))?;
+        //可能包含app_secret/access_token, 只有当前用户可读写
+        write_private_file(path, &path.with_extension("toml.tmp"), content.as_bytes())?;
+        Ok(())
+    }
+
+    /**
+     * 要使用的profile名: 指定的 > 配置文件中的default_profile > default
+     */
+    pub fn profile_name(&self, name: Option<&str>) -> String {
+        name.map(|n| n.to_string())
+            .or_else(|| self.default_profile.clone())
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+    }
+
+    /**
+     * 指定了profile但是配置文件中没有时报错, 都没有指定时允许没有配置(全部使用默认值)
+     */
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, YunPanError> {
+        let profile_name = self.profile_name(name);
+        match self.profiles.get(&profile_name) {
+            Some(profile) => Ok(profile.clone()),
+            None if name.is_none() && self.default_profile.is_none() => Ok(Profile::default()),
+            None => Err(YunPanError::InvalidArgument(format!("profile {} not found in config file", profile_name))),
+        }
+    }
+}
+
+/**
+ * profile list 的一行
+ */
+#[derive(Debug, Serialize)]
+pub struct ProfileInfo {
+    pub name: String,
+    pub default: bool,
+    pub logged_in: bool, //是否有login保存的凭证
+    pub app_root: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CliProfileList {
+    pub profiles: Vec<ProfileInfo>,
+}
+impl std::fmt::Display for CliProfileList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.profiles.is_empty() {
+            return write!(f, "no profiles, use `profile add <name>` to create one");
+        }
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{} {}{}{}",
+                if profile.default { "*" } else { " " },
+                profile.name,
+                if profile.logged_in { " (logged in)" } else { "" },
+                profile.app_root.as_deref().map(|r| format!(" app_root:{}", r)).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_names() {
+        for name in ["default", "archive", "work-2", "a_b"] {
+            assert!(check_profile_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", "../../evil", "a/b", "..", "a b", "名字"] {
+            assert!(matches!(check_profile_name(name), Err(YunPanError::InvalidArgument(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn missing_profile() {
+        let mut config = Config::default();
+        //没有配置文件也可以使用默认值
+        assert!(config.profile(None).is_ok());
+        assert!(matches!(config.profile(Some("work")), Err(YunPanError::InvalidArgument(_))));
+        config.default_profile = Some("work".to_string());
+        assert!(matches!(config.profile(None), Err(YunPanError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/**
+ * 断点续传下载的进度记录
+ * 下载中的数据写在 <本地文件>.part, 已完成的分段(seq)记录在旁边的 <本地文件>.part.json
+ * 远程文件(fs_id/大小/md5)以及分段大小都一致时才沿用之前的进度
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadJournal {
+    pub fs_id: u64,
+    pub file_size: u64,
+    pub md5: Option<String>,
+    pub chunk_size: u64,
+    pub completed: BTreeSet<u64>, //已经写入.part文件的分段序号
+    #[serde(skip)]
+    journal_path: PathBuf,
+}
+
+impl DownloadJournal {
+    pub fn part_path(local_path: &Path) -> PathBuf {
+        let mut name = local_path.as_os_str().to_owned();
+        name.push(".part");
+        PathBuf::from(name)
+    }
+
+    fn journal_path(local_path: &Path) -> PathBuf {
+        let mut name = local_path.as_os_str().to_owned();
+        name.push(".part.json");
+        PathBuf::from(name)
+    }
+
+    /**
+     * 读取之前的进度, 没有记录/.part文件不存在/远程文件有变化时返回一个新的空记录
+     */
+    pub async fn load(local_path: &Path, fs_id: u64, file_size: u64, md5: Option<String>, chunk_size: u64) -> DownloadJournal {
+        let journal_path = Self::journal_path(local_path);
+        let previous = if Self::part_path(local_path).exists() {
+            tokio::fs::read_to_string(&journal_path).await.ok()
+                .and_then(|content| serde_json::from_str::<DownloadJournal>(&content).ok())
+        } else {
+            None
+        };
+
+        match previous {
+            Some(mut journal) if journal.fs_id == fs_id && journal.file_size == file_size
+                && journal.md5 == md5 && journal.chunk_size == chunk_size => {
+                journal.journal_path = journal_path;
+                journal
+            },
+            _ => DownloadJournal {
+                fs_id,
+                file_size,
+                md5,
+                chunk_size,
+                completed: BTreeSet::new(),
+                journal_path,
+            },
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), std::io::Error> {
+        let content = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        //先写临时文件再rename, 避免中途被kill掉留下半个文件
+        let tmp_path = self.journal_path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, &self.journal_path).await
+    }
+
+    /**
+     * 记录一个已经完整写入的分段
+     */
+    pub async fn ack(&mut self, seq: u64) -> Result<(), std::io::Error> {
+        self.completed.insert(seq);
+        self.save().await
+    }
+
+    pub async fn remove(&self) -> Result<(), std::io::Error> {
+        if self.journal_path.exists() {
+            tokio::fs::remove_file(&self.journal_path).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn load_checks_remote_file() {
+        let local_path = std::env::temp_dir().join(format!("baidu_yunpan_test_{}_download.bin", std::process::id()));
+        let md5 = Some("e".repeat(32));
+        let load = |fs_id, file_size, md5: Option<String>, chunk_size| DownloadJournal::load(&local_path, fs_id, file_size, md5, chunk_size);
+
+        let mut journal = load(1, 100, md5.clone(), 10).await;
+        journal.ack(3).await.unwrap();
+        //没有.part文件时之前的进度没有意义
+        assert!(load(1, 100, md5.clone(), 10).await.completed.is_empty());
+
+        let part_path = DownloadJournal::part_path(&local_path);
+        std::fs::write(&part_path, b"").unwrap();
+        assert_eq!(load(1, 100, md5.clone(), 10).await.completed, BTreeSet::from([3]));
+        //远程文件或者分段大小变了都从头下载
+        assert!(load(2, 100, md5.clone(), 10).await.completed.is_empty());
+        assert!(load(1, 101, md5.clone(), 10).await.completed.is_empty());
+        assert!(load(1, 100, Some("f".repeat(32)), 10).await.completed.is_empty());
+        assert!(load(1, 100, md5.clone(), 20).await.completed.is_empty());
+
+        journal.remove().await.unwrap();
+        assert!(load(1, 100, md5, 10).await.completed.is_empty());
+        std::fs::remove_file(&part_path).unwrap();
+    }
+}
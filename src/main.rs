@@ -1,73 +1,728 @@
 mod yunpan_service;
 mod utils;
+mod upload_journal;
+mod download_journal;
+mod output;
+mod auth;
+mod config;
+mod progress;
 
 use yunpan_service::*;
+use auth::{BaiduOAuth, CliLoginResponse, Credentials, TokenProvider, TokenStore};
+use config::{check_profile_name, CliProfileList, Config, Profile, ProfileInfo, DEFAULT_PROFILE};
+use output::OutputFormat;
+use progress::{ProgressLogger, ProgressMode};
+use std::path::PathBuf;
 use std::time::Instant;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 
+/** 和 YunPanError::exit_code 对应 */
+const EXIT_CODES: &str = "Exit codes:
+  0 success          1 other errors          2 invalid arguments
+  3 auth expired     4 quota exceeded        5 invalid path / not found
+  6 already exists   7 rate limited          8 server busy / network error
+  9 local io error";
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = EXIT_CODES)]
 struct Args {
-    #[arg(short, long, default_value_t = String::from(""))]
-    access_token: String,
+    #[command(flatten)]
+    global: GlobalOpts,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+//所有子命令共用的选项
+#[derive(clap::Args, Debug)]
+struct GlobalOpts {
+    /// access_token, 不指定时依次从环境变量 BAIDU_YUNPAN_ACCESS_TOKEN(明确指定了profile时不使用), 配置文件和login保存的凭证中获取
+    #[arg(short, long, global = true)]
+    access_token: Option<String>,
+
+    /// 配置文件路径, 默认为 ~/.config/baidu_yunpan/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// 使用配置文件中的哪个profile, 不指定时从环境变量 BAIDU_YUNPAN_PROFILE 获取, 默认为配置文件中的default_profile
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// 应用目录, 相对的远程路径都基于这个目录; 不指定时依次从环境变量 BAIDU_YUNPAN_APP_ROOT 和配置文件获取, 默认为 /apps/asitanokibou
+    #[arg(long, global = true)]
+    app_root: Option<String>,
+
+    /// 超时/服务端繁忙等临时性错误时最多尝试的次数, 默认为配置文件中的max_attempts或5
+    #[arg(long, global = true)]
+    max_attempts: Option<u32>,
+
+    /// 上传/下载进度的显示方式: auto为终端中显示进度条, 否则(如CI日志)每隔几秒输出一行
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto, global = true)]
+    progress: ProgressMode,
+
+    /// 结果的输出格式
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    format: OutputFormat,
+
+    /// 输出更多日志 (-v: debug, -vv: trace)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// 只输出错误日志
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 上传文件
+    Upload(UploadArgs),
+    /// 下载文件
+    Download(DownloadArgs),
+    /// 列出远程目录
+    Ls(LsArgs),
+    /// 查看远程文件的信息 (大小/md5/分类/时间/下载地址)
+    Stat(StatArgs),
+    /// 复制远程文件或目录到另一个目录下
+    Cp(CopyMoveArgs),
+    /// 移动远程文件或目录到另一个目录下
+    Mv(CopyMoveArgs),
+    /// 重命名远程文件或目录
+    Rename(RenameArgs),
+    /// 删除远程文件或目录 (删除后在网盘回收站中)
+    Rm(RemoveArgs),
+    /// 创建远程目录
+    Mkdir(MkdirArgs),
+    /// 按关键字搜索远程文件
+    Find(FindArgs),
+    /// 查看网盘容量
+    Quota,
+    /// 查看当前登录的百度账号
+    Whoami,
+    /// 通过设备码授权登录, 保存access_token和refresh_token (保存到当前profile)
+    Login(LoginArgs),
+    /// 管理多个百度账号的配置
+    #[command(subcommand)]
+    Profile(ProfileCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileCommand {
+    /// 列出所有profile (* 为默认)
+    List,
+    /// 添加profile, 已存在时更新指定的配置项
+    Add(ProfileAddArgs),
+    /// 删除profile以及login保存的凭证
+    Remove {
+        name: String,
+    },
+    /// 设置默认的profile
+    Default {
+        name: String,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct ProfileAddArgs {
+    name: String,
+
+    /// 应用的AppKey
+    #[arg(long)]
+    app_key: Option<String>,
+
+    /// 应用的SecretKey
+    #[arg(long)]
+    app_secret: Option<String>,
 
-    /// 要上传的文件路径
+    /// 应用目录
+    #[arg(long = "root")]
+    app_root: Option<String>,
+
+    /// 同时设置为默认的profile
+    #[arg(long, default_value_t = false)]
+    default: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct LoginArgs {
+    /// 应用的AppKey, 不指定时依次从环境变量 BAIDU_YUNPAN_APP_KEY 和配置文件获取
+    #[arg(long)]
+    app_key: Option<String>,
+
+    /// 应用的SecretKey, 不指定时依次从环境变量 BAIDU_YUNPAN_APP_SECRET 和配置文件获取
+    #[arg(long)]
+    app_secret: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct UploadArgs {
+    /// 要上传的文件或目录路径 (目录会递归上传并保持目录结构)
     #[arg(short, long)]
     file: String,
 
-    /// 分片大小 (MB)
-    #[arg(short, long, default_value_t = 10)]
-    chunk_size: u64,
+    /// 分片大小 (MB, 4~1024), 默认为配置文件中的chunk_size或10
+    #[arg(short, long)]
+    chunk_size: Option<u64>,
 
-    /// 是否续传 TODO: 未实现
+    /// 是否续传 (复用上次中断时记录的upload_id, 只上传未完成的分片)
     #[arg(short, long, default_value_t = false)]
     resume: bool,
+
+    /// 同时上传的分片数, 默认为配置文件中的parallel或1
+    #[arg(short, long)]
+    parallel: Option<usize>,
+
+    /// 上传到的远程目录(文件名不变), 相对路径时基于应用目录
+    #[arg(long)]
+    remote_dir: Option<String>,
+
+    /// 上传到的远程完整路径, 相对路径时基于应用目录
+    #[arg(long, conflicts_with = "remote_dir")]
+    remote_path: Option<String>,
+
+    /// 远程文件已存在时的处理方式, 默认为配置文件中的on_conflict或overwrite
+    #[arg(long, value_enum)]
+    on_conflict: Option<ConflictPolicy>,
 }
 
+#[derive(clap::Args, Debug)]
+struct DownloadArgs {
+    /// 网盘中的文件路径 如 /apps/asitanokibou/a.zip, 相对路径时基于应用目录
+    remote_path: String,
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-    let file_path = &args.file;
-    let chunk_size = args.chunk_size * 1024 * 1024;
-    //let resume = args.resume;
-    let access_token = if !args.access_token.is_empty() {
-        args.access_token
+    /// 本地保存路径(文件或目录), 默认为当前目录
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// 每个Range请求的大小 (MB, 1~1024), 默认为配置文件中的chunk_size或10
+    #[arg(short, long)]
+    chunk_size: Option<u64>,
+
+    /// 同时下载的连接数, 默认为配置文件中的parallel或4
+    #[arg(short, long)]
+    parallel: Option<usize>,
+
+    /// 下载完成后不校验md5 (校验失败时保留了已下载的数据, 可以用这个选项接受它)
+    #[arg(long, default_value_t = false)]
+    no_verify: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct LsArgs {
+    /// 远程目录, 默认为应用目录, 相对路径时基于应用目录
+    dir: Option<String>,
+
+    /// 递归列出所有子目录
+    #[arg(short, long, default_value_t = false)]
+    recursive: bool,
+
+    /// 排序字段
+    #[arg(short, long, value_enum, default_value_t = ListOrder::Name)]
+    sort: ListOrder,
+
+    /// 降序排列
+    #[arg(long, default_value_t = false)]
+    desc: bool,
+
+    /// 只列出目录
+    #[arg(short, long, default_value_t = false)]
+    dirs_only: bool,
+
+    /// 以 1.5K 23.0M 这样的格式显示大小
+    #[arg(short = 'H', long, default_value_t = false)]
+    human: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct StatArgs {
+    /// 网盘中的文件或目录路径, 相对路径时基于应用目录
+    remote_path: String,
+
+    /// 同时显示缩略图地址 (只有图片有)
+    #[arg(long, default_value_t = false)]
+    thumb: bool,
+
+    /// 同时显示图片/视频的额外信息
+    #[arg(long, default_value_t = false)]
+    extra: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CopyMoveArgs {
+    /// 要复制/移动的远程路径, 可以指定多个, 相对路径时基于应用目录
+    #[arg(required = true, num_args = 1..)]
+    sources: Vec<String>,
+
+    /// 目标目录
+    dest_dir: String,
+
+    /// 目标已存在时的处理方式, 默认为fail
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::Fail)]
+    on_conflict: ConflictPolicy,
+
+    /// 强制异步执行并等待任务结束 (文件很多时使用)
+    #[arg(long = "async", default_value_t = false)]
+    force_async: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct RenameArgs {
+    /// 远程路径, 相对路径时基于应用目录
+    path: String,
+
+    /// 新的文件名 (不是路径)
+    new_name: String,
+
+    /// 新文件名已存在时的处理方式, 默认为fail
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::Fail)]
+    on_conflict: ConflictPolicy,
+
+    /// 强制异步执行并等待任务结束
+    #[arg(long = "async", default_value_t = false)]
+    force_async: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct RemoveArgs {
+    /// 要删除的远程路径, 可以指定多个, 相对路径时基于应用目录
+    #[arg(required = true)]
+    paths: Vec<String>,
+
+    /// 强制异步执行并等待任务结束 (文件很多时使用)
+    #[arg(long = "async", default_value_t = false)]
+    force_async: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct MkdirArgs {
+    /// 远程目录路径, 相对路径时基于应用目录
+    path: String,
+
+    /// 同时创建不存在的上级目录, 目录已存在时不报错
+    #[arg(short, long, default_value_t = false)]
+    parents: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct FindArgs {
+    /// 搜索的关键字 (匹配文件名)
+    key: String,
+
+    /// 搜索的远程目录, 默认为应用目录, 相对路径时基于应用目录
+    #[arg(short, long)]
+    dir: Option<String>,
+
+    /// 不搜索子目录
+    #[arg(long, default_value_t = false)]
+    no_recursion: bool,
+
+    /// 只搜索指定分类的文件
+    #[arg(short, long, value_enum)]
+    category: Option<FileCategory>,
+
+    /// 只返回第几页 (从1开始), 默认返回所有页
+    #[arg(long)]
+    page: Option<u32>,
+
+    /// 每页的数量 (最大1000), 指定--page时有效
+    #[arg(long, default_value_t = 500)]
+    num: u32,
+
+    /// 以 1.5K 23.0M 这样的格式显示大小
+    #[arg(short = 'H', long, default_value_t = false)]
+    human: bool,
+}
+
+fn init_logger(global: &GlobalOpts) {
+    //-vv 之前只输出本程序的日志, 依赖库(reqwest/hyper等)只输出warn以上
+    let filter = if global.quiet {
+        "error".to_string()
     } else {
-        //1从环境变量中获取
-        std::env::var("BAIDU_YUNPAN_ACCESS_TOKEN")
-            .unwrap_or_else(|_e| {      
-                //2从配置文件中获取 .baidu_yunpan (当前用户home目录下)
-                dirs::home_dir()
-                    .and_then(|h| std::fs::read_to_string(h.join(".baidu_yunpan")).ok() /*ok: Result->Option */)
-                    //.unwrap_or_default()   //String::default() 空串 
-                    .expect("Cannot find access_token,Please specify in command line or config file .baidu_yunpan") //没有指定的话, 直接panic
-            })
+        match global.verbose {
+            0 => "warn,baidu_yunpan_cli=info".to_string(),
+            1 => "warn,baidu_yunpan_cli=debug".to_string(),
+            _ => "trace".to_string(),
+        }
     };
-    let access_token = access_token.trim().to_string();
-    //println!("access_token:{}", access_token);
+    //RUST_LOG 优先
+    let logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter))
+        .format_target(false)
+        .build();
+    //显示进度条时输出日志不能打断进度条
+    ProgressLogger::init(logger);
+}
 
-    let start_time = Instant::now();
+fn load_config(global: &GlobalOpts) -> Result<(PathBuf, Config), YunPanError> {
+    let config_path = global.config.clone().or_else(Config::default_path)
+        .ok_or_else(|| YunPanError::Biz("cannot find config directory, please specify --config".to_string()))?;
+    let config = Config::load(&config_path)?;
+    Ok((config_path, config))
+}
+
+/**
+ * 命令行或环境变量中明确指定的profile
+ */
+fn explicit_profile(global: &GlobalOpts) -> Option<String> {
+    global.profile.clone().or_else(|| std::env::var("BAIDU_YUNPAN_PROFILE").ok())
+}
 
-    let yunpan_service = YunPanService::new(access_token);
- 
-    let result= yunpan_service.upload(
-        CliUploadRequest::new(file_path, chunk_size)).await ;
+/**
+ * 当前使用的profile: --profile > BAIDU_YUNPAN_PROFILE > 配置文件中的default_profile > default
+ */
+fn resolve_profile(global: &GlobalOpts, config: &Config) -> Result<(String, Profile), YunPanError> {
+    let profile_name = explicit_profile(global);
+    check_profile_name(&config.profile_name(profile_name.as_deref()))?;
+    let profile = config.profile(profile_name.as_deref())?;
+    Ok((config.profile_name(profile_name.as_deref()), profile))
+}
 
-    let elapsed = start_time.elapsed();
-    println!("Upload took {:?}", elapsed);
-
-    match result {
-        Ok(_) => println!("Upload successful"),
-        Err(e) => {
-            match e {
-                YunPanError::Reqwest(err) => println!("Reqwest error: {}", err),
-                YunPanError::Io(err) => println!("Io error: {}", err),
-                YunPanError::Biz(err) => println!("Biz error: {}", err),
-                YunPanError::Serde(err) => println!("Serde error: {}", err),
+/**
+ * 明确指定了profile时只使用这个profile自己的凭证, 避免用别的账号的token操作
+ */
+fn resolve_access_token(global: &GlobalOpts, profile_name: &str, profile: &Profile) -> Option<TokenProvider> {
+    let env_token = std::env::var("BAIDU_YUNPAN_ACCESS_TOKEN").ok();
+    let explicit = explicit_profile(global).is_some();
+    if let Some(access_token) = fixed_access_token(global.access_token.as_deref(), env_token.as_deref(), explicit, profile_name, profile) {
+        return Some(TokenProvider::fixed(access_token));
+    }
+    //3从当前profile login保存的凭证中获取 (快过期时自动刷新)
+    if let Some(store) = TokenStore::for_profile(profile_name)
+        && let Some(credentials) = store.load() {
+        return Some(TokenProvider::refreshable(credentials, store));
+    }
+    legacy_access_token(profile_name).map(TokenProvider::fixed)
+}
+
+/**
+ * 固定的access_token: 命令行 > 环境变量(没有明确指定profile时) > profile配置
+ */
+fn fixed_access_token(cli_token: Option<&str>, env_token: Option<&str>, explicit: bool, profile_name: &str, profile: &Profile) -> Option<String> {
+    //0命令行指定
+    if let Some(access_token) = cli_token {
+        return Some(access_token.trim().to_string());
+    }
+    //1从环境变量中获取
+    if let Some(access_token) = env_token {
+        if !explicit {
+            return Some(access_token.trim().to_string());
+        }
+        log::warn!("BAIDU_YUNPAN_ACCESS_TOKEN is ignored because profile {} is specified", profile_name);
+    }
+    //2从配置文件的profile中获取
+    profile.access_token.as_deref().map(|access_token| access_token.trim().to_string())
+}
+
+/**
+ * 4兼容旧版本: home目录下 .baidu_yunpan 文件的内容就是access_token, 只用于default profile
+ */
+fn legacy_access_token(profile_name: &str) -> Option<String> {
+    if profile_name != DEFAULT_PROFILE {
+        return None;
+    }
+    let legacy_path = dirs::home_dir()?.join(".baidu_yunpan");
+    let access_token = std::fs::read_to_string(&legacy_path).ok() /*ok: Result->Option */
+        .map(|access_token| access_token.trim().to_string())
+        .filter(|access_token| !access_token.is_empty())?;
+    log::warn!("{} is deprecated, please run login or put access_token into config.toml", legacy_path.display());
+    Some(access_token)
+}
+
+fn service_options(global: &GlobalOpts, profile: &Profile) -> ServiceOptions {
+    ServiceOptions {
+        app_root: global.app_root.clone()
+            .or_else(|| std::env::var("BAIDU_YUNPAN_APP_ROOT").ok())
+            .or_else(|| profile.app_root.clone()),
+        proxy: profile.proxy.clone(),
+        upload_limit: profile.upload_limit.map(|kb| kb * 1024),
+        download_limit: profile.download_limit.map(|kb| kb * 1024),
+        max_attempts: global.max_attempts.or(profile.max_attempts),
+        progress: if global.quiet { ProgressMode::None } else { global.progress },
+    }
+}
+
+async fn login(args: LoginArgs, profile_name: &str, profile: &Profile, format: OutputFormat) -> Result<(), YunPanError> {
+    let app_key = args.app_key.or_else(|| std::env::var("BAIDU_YUNPAN_APP_KEY").ok())
+        .or_else(|| profile.app_key.clone())
+        .ok_or_else(|| YunPanError::Biz("app key is required, use --app-key, BAIDU_YUNPAN_APP_KEY or app_key in config".to_string()))?;
+    let app_secret = args.app_secret.or_else(|| std::env::var("BAIDU_YUNPAN_APP_SECRET").ok())
+        .or_else(|| profile.app_secret.clone())
+        .ok_or_else(|| YunPanError::Biz("app secret is required, use --app-secret, BAIDU_YUNPAN_APP_SECRET or app_secret in config".to_string()))?;
+    let store = TokenStore::for_profile(profile_name)
+        .ok_or_else(|| YunPanError::Biz("cannot find config directory to save token".to_string()))?;
+
+    let oauth = BaiduOAuth::new(&app_key, &app_secret);
+    let device = oauth.device_code().await?;
+    //提示信息输出到stderr, 不影响json格式的输出
+    eprintln!("Please open {} and enter the code: {}", device.verification_url, device.user_code);
+    if let Some(qrcode_url) = &device.qrcode_url {
+        eprintln!("or scan the QR code: {}", qrcode_url);
+    }
+
+    let token = oauth.poll_token(&device).await?;
+    let response = CliLoginResponse {
+        token_path: store.path().clone(),
+        expires_at: token.expires_at,
+        scope: token.scope.clone(),
+    };
+    store.save(&Credentials { app_key, app_secret, token })?;
+    format.print(&response);
+    Ok(())
+}
+
+fn profile(command: ProfileCommand, config_path: &std::path::Path, mut config: Config, format: OutputFormat) -> Result<(), YunPanError> {
+    match &command {
+        ProfileCommand::Add(ProfileAddArgs { name, .. }) | ProfileCommand::Remove { name } | ProfileCommand::Default { name } => check_profile_name(name)?,
+        ProfileCommand::List => {},
+    }
+    match command {
+        ProfileCommand::List => {
+            let default_name = config.profile_name(None);
+            let profiles = config.profiles.iter().map(|(name, profile)| ProfileInfo {
+                name: name.clone(),
+                default: *name == default_name,
+                logged_in: TokenStore::for_profile(name).map(|store| store.exists()).unwrap_or(false),
+                app_root: profile.app_root.clone(),
+            }).collect();
+            format.print(&CliProfileList { profiles });
+            return Ok(());
+        },
+        ProfileCommand::Add(args) => {
+            let profile = config.profiles.entry(args.name.clone()).or_default();
+            if args.app_key.is_some() {
+                profile.app_key = args.app_key;
+            }
+            if args.app_secret.is_some() {
+                profile.app_secret = args.app_secret;
+            }
+            if args.app_root.is_some() {
+                profile.app_root = args.app_root;
+            }
+            if args.default || config.default_profile.is_none() {
+                config.default_profile = Some(args.name.clone());
             }
+            log::info!("profile {} saved", args.name);
+        },
+        ProfileCommand::Remove { name } => {
+            if config.profiles.remove(&name).is_none() {
+                return Err(YunPanError::InvalidArgument(format!("profile {} not found", name)));
+            }
+            if config.default_profile.as_deref() == Some(name.as_str()) {
+                config.default_profile = None;
+            }
+            if let Some(store) = TokenStore::for_profile(&name) {
+                store.remove()?;
+            }
+            log::info!("profile {} removed", name);
+        },
+        ProfileCommand::Default { name } => {
+            if !config.profiles.contains_key(&name) {
+                return Err(YunPanError::InvalidArgument(format!("profile {} not found", name)));
+            }
+            config.default_profile = Some(name.clone());
+            log::info!("default profile is now {}", name);
+        },
+    }
+    config.save(config_path)
+}
+
+/** 分片大小(MB)的上限, 避免换算成字节时溢出 */
+const MAX_CHUNK_SIZE_MB: u64 = 1024;
+
+/**
+ * 命令行或配置文件中的分片大小(MB)换算成字节, 超出[min_mb, MAX_CHUNK_SIZE_MB]时返回参数错误
+ */
+fn chunk_size(chunk_size_mb: Option<u64>, profile: &Profile, min_mb: u64) -> Result<u64, YunPanError> {
+    let mb = chunk_size_mb.or(profile.chunk_size).unwrap_or(10);
+    if !(min_mb..=MAX_CHUNK_SIZE_MB).contains(&mb) {
+        return Err(YunPanError::InvalidArgument(
+            format!("chunk size must be between {}MB and {}MB, got {}MB", min_mb, MAX_CHUNK_SIZE_MB, mb)));
+    }
+    Ok(mb * 1024 * 1024)
+}
+
+async fn upload(service: &YunPanService, args: UploadArgs, profile: &Profile, format: OutputFormat) -> Result<(), YunPanError> {
+    //第一个分片至少4MB(小于4MB的文件一次上传)
+    let chunk_size = chunk_size(args.chunk_size, profile, 4)?;
+    let parallel = args.parallel.or(profile.parallel).unwrap_or(1);
+    let on_conflict = args.on_conflict.or(profile.on_conflict).unwrap_or_default();
+    let is_dir = std::path::Path::new(&args.file).is_dir();
+    let request = CliUploadRequest::new(&args.file, chunk_size, args.resume, parallel, args.remote_dir, args.remote_path, on_conflict);
+    if is_dir {
+        let summary = service.upload_dir(request).await?;
+        format.print(&summary);
+        if summary.has_failures() {
+            return Err(YunPanError::Biz(format!("some files under {} failed to upload", args.file)));
         }
-    };  
+    } else {
+        let response = service.upload(request).await?;
+        format.print(&response);
+    }
+    Ok(())
+}
+
+async fn download(service: &YunPanService, args: DownloadArgs, profile: &Profile, format: OutputFormat) -> Result<(), YunPanError> {
+    let chunk_size = chunk_size(args.chunk_size, profile, 1)?;
+    let parallel = args.parallel.or(profile.parallel).unwrap_or(4);
+    let response = service.download(
+        CliDownloadRequest::new(&args.remote_path, args.output, chunk_size, parallel, !args.no_verify)).await?;
+    format.print(&response);
+    Ok(())
+}
+
+async fn ls(service: &YunPanService, args: LsArgs, format: OutputFormat) -> Result<(), YunPanError> {
+    let options = ListOptions {
+        recursive: args.recursive,
+        order: args.sort,
+        desc: args.desc,
+        dirs_only: args.dirs_only,
+    };
+    let files = service.list(args.dir.as_deref(), &options).await?;
+    format.print(&CliFileList::new(files, args.human));
+    Ok(())
+}
+
+async fn find(service: &YunPanService, args: FindArgs, format: OutputFormat) -> Result<(), YunPanError> {
+    let recursion = !args.no_recursion;
+    let files = match args.page {
+        Some(page) => {
+            let (files, has_more) = service.search_page(&args.key, args.dir.as_deref(), recursion, args.category, page, args.num).await?;
+            if has_more {
+                log::info!("more results on page {}", page.max(1) + 1);
+            }
+            files
+        },
+        None => service.search(&args.key, args.dir.as_deref(), recursion, args.category).await?,
+    };
+    format.print(&CliFileList::new(files, args.human));
+    Ok(())
+}
+
+/**
+ * 输出文件管理操作的结果, 有失败的文件时返回错误
+ */
+fn print_file_manager_response(response: CliFileManagerResponse, format: OutputFormat) -> Result<(), YunPanError> {
+    format.print(&response);
+    if response.has_failures() {
+        return Err(YunPanError::Biz("some files failed".to_string()));
+    }
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<(), YunPanError> {
+    let format = args.global.format;
+    let (config_path, config) = load_config(&args.global)?;
+
+    //管理profile时不需要解析当前的profile (可能还不存在)
+    let command = match args.command {
+        Command::Profile(profile_command) => return profile(profile_command, &config_path, config, format),
+        command => command,
+    };
+    let (profile_name, profile) = resolve_profile(&args.global, &config)?;
+
+    //登录不需要access_token
+    let command = match command {
+        Command::Login(login_args) => return login(login_args, &profile_name, &profile, format).await,
+        command => command,
+    };
+
+    //没有指定的话, 直接退出
+    let tokens = resolve_access_token(&args.global, &profile_name, &profile)
+        .ok_or_else(|| YunPanError::Biz("Cannot find access_token, please run login or specify it in command line or config file".to_string()))?;
 
-}
\ No newline at end of file
+    let yunpan_service = YunPanService::new(tokens, service_options(&args.global, &profile))?;
+
+    match command {
+        Command::Upload(upload_args) => upload(&yunpan_service, upload_args, &profile, format).await,
+        Command::Download(download_args) => download(&yunpan_service, download_args, &profile, format).await,
+        Command::Ls(ls_args) => ls(&yunpan_service, ls_args, format).await,
+        Command::Stat(stat_args) => {
+            let response = yunpan_service.stat(&stat_args.remote_path, stat_args.thumb, stat_args.extra).await?;
+            format.print(&response);
+            Ok(())
+        },
+        Command::Cp(args) => print_file_manager_response(
+            yunpan_service.copy_files(&args.sources, &args.dest_dir, args.on_conflict, args.force_async).await?, format),
+        Command::Mv(args) => print_file_manager_response(
+            yunpan_service.move_files(&args.sources, &args.dest_dir, args.on_conflict, args.force_async).await?, format),
+        Command::Rename(args) => print_file_manager_response(
+            yunpan_service.rename(&args.path, &args.new_name, args.on_conflict, args.force_async).await?, format),
+        Command::Rm(args) => print_file_manager_response(
+            yunpan_service.delete(&args.paths, args.force_async).await?, format),
+        Command::Mkdir(args) => {
+            let response = yunpan_service.mkdir(&args.path, args.parents).await?;
+            format.print(&response);
+            Ok(())
+        },
+        Command::Find(find_args) => find(&yunpan_service, find_args, format).await,
+        Command::Quota => {
+            format.print(&yunpan_service.quota().await?);
+            Ok(())
+        },
+        Command::Whoami => {
+            format.print(&yunpan_service.user_info().await?);
+            Ok(())
+        },
+        Command::Login(_) | Command::Profile(_) => unreachable!("handled before resolving access_token"),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    init_logger(&args.global);
+
+    let start_time = Instant::now();
+    let result = run(args).await;
+    let elapsed = start_time.elapsed();
+    log::info!("took {:?}", elapsed);
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        if e.is_retryable() {
+            eprintln!("This error is temporary, retrying later may succeed");
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_token_precedence() {
+        let profile = Profile { access_token: Some(" from-profile\n".to_string()), ..Profile::default() };
+        let token = |cli, env, explicit, profile: &Profile| fixed_access_token(cli, env, explicit, "work", profile);
+
+        //命令行优先于环境变量和profile
+        assert_eq!(token(Some("cli"), Some("env"), true, &profile).as_deref(), Some("cli"));
+        //没有明确指定profile时环境变量优先于profile
+        assert_eq!(token(None, Some(" env "), false, &profile).as_deref(), Some("env"));
+        //明确指定了profile时忽略环境变量
+        assert_eq!(token(None, Some("env"), true, &profile).as_deref(), Some("from-profile"));
+        assert_eq!(token(None, Some("env"), true, &Profile::default()), None);
+        assert_eq!(token(None, None, false, &Profile::default()), None);
+
+        //旧版本的 ~/.baidu_yunpan 只用于default profile
+        assert_eq!(legacy_access_token("work"), None);
+    }
+
+    #[test]
+    fn chunk_size_validation() {
+        let profile = Profile { chunk_size: Some(8), ..Profile::default() };
+        //命令行 > profile > 默认10MB
+        assert_eq!(chunk_size(Some(16), &profile, 4).unwrap(), 16 * 1024 * 1024);
+        assert_eq!(chunk_size(None, &profile, 4).unwrap(), 8 * 1024 * 1024);
+        assert_eq!(chunk_size(None, &Profile::default(), 4).unwrap(), 10 * 1024 * 1024);
+
+        //上传至少4MB, 下载至少1MB
+        assert!(matches!(chunk_size(Some(2), &profile, 4), Err(YunPanError::InvalidArgument(_))));
+        assert_eq!(chunk_size(Some(1), &profile, 1).unwrap(), 1024 * 1024);
+        assert!(matches!(chunk_size(Some(0), &profile, 1), Err(YunPanError::InvalidArgument(_))));
+        assert!(matches!(chunk_size(Some(MAX_CHUNK_SIZE_MB + 1), &profile, 1), Err(YunPanError::InvalidArgument(_))));
+        //配置文件中的值同样检查
+        let profile = Profile { chunk_size: Some(u64::MAX), ..Profile::default() };
+        assert!(matches!(chunk_size(None, &profile, 4), Err(YunPanError::InvalidArgument(_))));
+    }
+}
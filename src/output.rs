@@ -0,0 +1,24 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+/**
+ * 命令结果的输出格式: text 给人看, json 方便脚本处理
+ */
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn print<T: Serialize + std::fmt::Display>(&self, value: &T) {
+        match self {
+            OutputFormat::Text => println!("{}", value),
+            OutputFormat::Json => match serde_json::to_string_pretty(value) {
+                Ok(json) => println!("{}", json),
+                Err(e) => log::error!("failed to serialize output: {}", e),
+            },
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::IsTerminal;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use crate::utils::human_size;
+
+/**
+ * 进度的显示方式
+ * auto: 终端中显示进度条, 输出被重定向(CI日志等)时定期输出一行文字
+ */
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressMode {
+    #[default]
+    Auto,
+    Bar,
+    Plain,
+    None,
+}
+
+/**
+ * 所有进度条都加到这里, 输出日志时先把进度条擦掉, 输出完再重新画, 日志不会把进度条打断
+ */
+fn bars() -> &'static MultiProgress {
+    static BARS: OnceLock<MultiProgress> = OnceLock::new();
+    BARS.get_or_init(MultiProgress::new)
+}
+
+/**
+ * 包装env_logger, 有进度条显示时通过MultiProgress::suspend输出日志
+ */
+pub struct ProgressLogger {
+    inner: env_logger::Logger,
+}
+
+impl ProgressLogger {
+    /** 替代 env_logger::Builder::init */
+    pub fn init(inner: env_logger::Logger) {
+        let max_level = inner.filter();
+        if log::set_boxed_logger(Box::new(ProgressLogger { inner })).is_ok() {
+            log::set_max_level(max_level);
+        }
+    }
+}
+
+impl log::Log for ProgressLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.inner.matches(record) {
+            bars().suspend(|| self.inner.log(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/** plain模式下输出进度的间隔 */
+const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/**
+ * 一个文件上传/下载的字节进度, 并发的分片共享同一个进度 (clone之后指向同一个)
+ */
+#[derive(Clone)]
+pub struct Progress {
+    inner: Arc<ProgressInner>,
+}
+
+enum ProgressInner {
+    Bar(ProgressBar),
+    Plain(PlainProgress),
+    Hidden,
+}
+
+struct PlainProgress {
+    label: String,
+    total: u64,
+    done: AtomicU64,
+    initial: u64,//之前已经完成的部分(续传), 不计入速度
+    start: Instant,
+    last_print: std::sync::Mutex<Instant>,
+}
+
+impl Progress {
+    /**
+     * label: 显示的名称 total: 总字节数 done: 已经完成的字节数(续传时)
+     */
+    pub fn new(mode: ProgressMode, label: &str, total: u64, done: u64) -> Self {
+        let mode = match mode {
+            ProgressMode::Auto if std::io::stderr().is_terminal() => ProgressMode::Bar,
+            ProgressMode::Auto => ProgressMode::Plain,
+            mode => mode,
+        };
+        let inner = match mode {
+            ProgressMode::Bar => {
+                let bar = bars().add(ProgressBar::new(total));
+                bar.set_style(ProgressStyle::with_template(
+                    "{msg} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}")
+                    .unwrap()
+                    .progress_chars("=> "));
+                bar.set_message(label.to_string());
+                bar.set_position(done);
+                bar.reset_eta();
+                ProgressInner::Bar(bar)
+            },
+            ProgressMode::Plain => ProgressInner::Plain(PlainProgress {
+                label: label.to_string(),
+                total,
+                done: AtomicU64::new(done),
+                initial: done,
+                start: Instant::now(),
+                last_print: std::sync::Mutex::new(Instant::now()),
+            }),
+            ProgressMode::None | ProgressMode::Auto => ProgressInner::Hidden,
+        };
+        Progress { inner: Arc::new(inner) }
+    }
+
+    pub fn inc(&self, bytes: u64) {
+        match self.inner.as_ref() {
+            ProgressInner::Bar(bar) => bar.inc(bytes),
+            ProgressInner::Plain(plain) => {
+                plain.done.fetch_add(bytes, Ordering::Relaxed);
+                let mut last_print = plain.last_print.lock().unwrap();
+                if last_print.elapsed() >= PLAIN_INTERVAL {
+                    *last_print = Instant::now();
+                    plain.print();
+                }
+            },
+            ProgressInner::Hidden => {},
+        }
+    }
+
+    /**
+     * 请求失败时撤回这次已经计入的字节 (重试时会重新计入)
+     */
+    pub fn rollback(&self, bytes: u64) {
+        match self.inner.as_ref() {
+            ProgressInner::Bar(bar) => bar.set_position(bar.position().saturating_sub(bytes)),
+            ProgressInner::Plain(plain) => {
+                let _ = plain.done.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |done| Some(done.saturating_sub(bytes)));
+            },
+            ProgressInner::Hidden => {},
+        }
+    }
+
+    pub fn finish(&self) {
+        match self.inner.as_ref() {
+            //结果会单独输出, 进度条不用保留 (目录上传时会有很多个文件)
+            ProgressInner::Bar(bar) => bar.finish_and_clear(),
+            ProgressInner::Plain(plain) => plain.print(),
+            ProgressInner::Hidden => {},
+        }
+    }
+}
+
+impl Drop for ProgressInner {
+    /** 从MultiProgress中移除, 失败提前返回时也一样, 不会越积越多 */
+    fn drop(&mut self) {
+        if let ProgressInner::Bar(bar) = self {
+            bar.finish_and_clear();
+            bars().remove(bar);
+        }
+    }
+}
+
+impl PlainProgress {
+    fn print(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_secs_f64().max(0.001);
+        let speed = done.saturating_sub(self.initial) as f64 / elapsed;
+        let percent = if self.total == 0 { 100.0 } else { done as f64 * 100.0 / self.total as f64 };
+        let eta = if speed > 0.0 {
+            format!("{}s", (self.total.saturating_sub(done) as f64 / speed).ceil() as u64)
+        } else {
+            "-".to_string()
+        };
+        eprintln!("{}: {}/{} ({:.1}%) {:.2}MB/s ETA {}",
+            self.label, human_size(done), human_size(self.total), percent, speed / 1024.0 / 1024.0, eta);
+    }
+}
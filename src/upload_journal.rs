@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use crate::utils::FileHashes;
+
+/**
+ * 断点续传的本地记录(journal)
+ * 保存在 <本地数据目录>/baidu_yunpan/resume/<md5(本地路径|远程路径)>.json (linux下为~/.local/share)
+ * 源文件的大小,修改时间以及分片大小都一致时才认为可以续传
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadJournal {
+    pub file_path: String, //源文件的绝对路径
+    pub remote_path: String, //上传到的远程路径
+    pub file_size: u64,
+    pub mtime: u64, //源文件修改时间(秒)
+    pub chunk_size: u64,
+    pub block_list: Vec<String>, //每个分片的md5 按seq排序
+    #[serde(default)]
+    pub content_md5: Option<String>, //整个文件的md5(秒传用) 旧版本的记录中没有
+    #[serde(default)]
+    pub slice_md5: Option<String>, //文件前256KB的md5(秒传用) 旧版本的记录中没有
+    pub upload_id: String,
+    pub uploaded: BTreeSet<u64>, //服务端已经确认的分片序号
+}
+
+impl UploadJournal {
+    pub fn new(
+        file_path: &str,
+        remote_path: &str,
+        file_size: u64,
+        mtime: u64,
+        chunk_size: u64,
+        hashes: &FileHashes,
+        upload_id: &str,
+    ) -> Self {
+        UploadJournal {
+            file_path: file_path.to_string(),
+            remote_path: remote_path.to_string(),
+            file_size,
+            mtime,
+            chunk_size,
+            block_list: hashes.block_list.clone(),
+            content_md5: Some(hashes.content_md5.clone()),
+            slice_md5: Some(hashes.slice_md5.clone()),
+            upload_id: upload_id.to_string(),
+            uploaded: BTreeSet::new(),
+        }
+    }
+
+    /**
+     * 记录中保存的md5, 续传时不用再读一遍文件; 旧版本的记录缺少整个文件的md5时返回None
+     */
+    pub fn hashes(&self) -> Option<FileHashes> {
+        Some(FileHashes {
+            block_list: self.block_list.clone(),
+            content_md5: self.content_md5.clone()?,
+            slice_md5: self.slice_md5.clone()?,
+        })
+    }
+
+    fn journal_path(file_path: &str, remote_path: &str) -> Option<PathBuf> {
+        let key = md5::compute(format!("{}|{}", file_path, remote_path));
+        dirs::data_local_dir().map(|d| d.join("baidu_yunpan").join("resume").join(format!("{:x}.json", key)))
+    }
+
+    /**
+     * 读取之前保存的记录, 源文件有变化(大小/修改时间/分片大小不同)时当作没有记录
+     */
+    pub async fn load(file_path: &str, remote_path: &str, file_size: u64, mtime: u64, chunk_size: u64) -> Option<UploadJournal> {
+        let path = Self::journal_path(file_path, remote_path)?;
+        Self::load_from(&path, file_path, file_size, mtime, chunk_size).await
+    }
+
+    async fn load_from(path: &Path, file_path: &str, file_size: u64, mtime: u64, chunk_size: u64) -> Option<UploadJournal> {
+        let content = tokio::fs::read_to_string(path).await.ok()?;
+        let journal = match serde_json::from_str::<UploadJournal>(&content) {
+            Ok(journal) => journal,
+            Err(e) => {
+                log::warn!("ignore broken resume journal of {}: {}", file_path, e);
+                return None;
+            }
+        };
+        if journal.file_size == file_size && journal.mtime == mtime && journal.chunk_size == chunk_size {
+            Some(journal)
+        } else {
+            log::info!("{} has been changed since last upload, start over", file_path);
+            None
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), std::io::Error> {
+        let path = match Self::journal_path(&self.file_path, &self.remote_path) {
+            Some(path) => path,
+            None => return Ok(()), //没有数据目录就不记录了
+        };
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let content = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        //先写临时文件再rename, 避免中途被kill掉留下半个文件
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+
+    /**
+     * 记录一个已经上传成功的分片
+     */
+    pub async fn ack(&mut self, seq: u64) -> Result<(), std::io::Error> {
+        self.uploaded.insert(seq);
+        self.save().await
+    }
+
+    pub async fn remove(&self) -> Result<(), std::io::Error> {
+        if let Some(path) = Self::journal_path(&self.file_path, &self.remote_path)
+            && path.exists() {
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal() -> UploadJournal {
+        let hashes = FileHashes {
+            block_list: vec!["a".repeat(32), "b".repeat(32)],
+            content_md5: "c".repeat(32),
+            slice_md5: "d".repeat(32),
+        };
+        let mut journal = UploadJournal::new("/tmp/a.bin", "/apps/x/a.bin", 100, 1700000000, 4 * 1024 * 1024, &hashes, "upload-id");
+        journal.uploaded.insert(0);
+        journal
+    }
+
+    #[tokio::test]
+    async fn load_checks_source_file() {
+        let path = std::env::temp_dir().join(format!("baidu_yunpan_test_{}_upload_journal.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string(&journal()).unwrap()).unwrap();
+        let load = |file_size, mtime, chunk_size| UploadJournal::load_from(&path, "/tmp/a.bin", file_size, mtime, chunk_size);
+
+        let loaded = load(100, 1700000000, 4 * 1024 * 1024).await.unwrap();
+        assert_eq!(loaded.upload_id, "upload-id");
+        assert_eq!(loaded.uploaded, BTreeSet::from([0]));
+        assert_eq!(loaded.hashes().unwrap().content_md5, "c".repeat(32));
+        //大小/修改时间/分片大小任何一个变了都从头上传
+        assert!(load(101, 1700000000, 4 * 1024 * 1024).await.is_none());
+        assert!(load(100, 1700000001, 4 * 1024 * 1024).await.is_none());
+        assert!(load(100, 1700000000, 8 * 1024 * 1024).await.is_none());
+
+        //旧版本的记录没有md5字段, 仍然可以续传
+        let mut old = serde_json::to_value(journal()).unwrap();
+        old.as_object_mut().unwrap().remove("content_md5");
+        old.as_object_mut().unwrap().remove("slice_md5");
+        std::fs::write(&path, old.to_string()).unwrap();
+        let loaded = load(100, 1700000000, 4 * 1024 * 1024).await.unwrap();
+        assert!(loaded.hashes().is_none());
+
+        std::fs::write(&path, "{broken").unwrap();
+        assert!(load(100, 1700000000, 4 * 1024 * 1024).await.is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
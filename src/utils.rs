@@ -1,6 +1,83 @@
+use bytes::Bytes;
+use futures::Stream;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::time::Instant;
 
+/**
+ * 简单的限速器: 每次发送/接收前预约一段时间, 所有并发的请求共享, 保证平均速度不超过bytes_per_sec
+ */
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    next: tokio::sync::Mutex<Instant>,//下一次可以开始的时间
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /** 按限速传输bytes需要的时间 */
+    pub fn duration_for(&self, bytes: u64) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64)
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let start = {
+            let mut next = self.next.lock().await;
+            let start = (*next).max(Instant::now());
+            *next = start + self.duration_for(bytes);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
+
+/**
+ * 临时性错误的重试策略: 指数退避 base_delay * 2^(n-1), 最大不超过max_delay, 再加上随机抖动避免并发的请求同时重试
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,//最多尝试的次数(包括第一次)
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /**
+     * 第attempt次失败后等待的时间: 退避时间的一半固定, 另一半随机
+     */
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay
+            .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        half + half.mul_f64(random_fraction())
+    }
+}
+
+/**
+ * [0, 1) 的随机数, 只用于抖动 不需要密码学安全 (每个RandomState的种子都是随机的)
+ */
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let value = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
 
 pub async fn md5_sum_part(file_path: &str,start:u64,size:u64) -> Result<String, std::io::Error> { 
     let mut file = tokio::fs::File::open(file_path).await?;
@@ -11,7 +88,9 @@ pub async fn md5_sum_part(file_path: &str,start:u64,size:u64) -> Result<String,
 
     let mut remaining_size = size;
     while remaining_size > 0 {
-        let bytes_read = file.read(&mut buffer).await?;
+        //不能读超过size的部分
+        let len = std::cmp::min(remaining_size, buffer.len() as u64) as usize;
+        let bytes_read = file.read(&mut buffer[..len]).await?;
         if bytes_read == 0 {
             break;
         }
@@ -29,6 +108,175 @@ pub async fn md5_sum(file_path: &str) -> Result<String, std::io::Error> {
     return md5_sum_part(file_path, 0, size).await;
 }
 
+/**
+ * 网盘接口返回的md5有些是"加密"过的(第10个字符是g-v的字母), 还原成文件内容的md5
+ * 还原方法和网页端一致: 第10个字符减去'g'得到原来的十六进制位, 每一位和 (位置 & 15) 异或, 再交换前后各8位
+ * 不是这种格式时原样返回(小写)
+ */
+pub fn decode_server_md5(md5: &str) -> String {
+    let md5 = md5.to_ascii_lowercase();
+    let bytes = md5.as_bytes();
+    let encrypted = bytes.len() == 32
+        && (b'g'..=b'v').contains(&bytes[9])
+        && bytes.iter().enumerate().all(|(i, c)| i == 9 || c.is_ascii_hexdigit());
+    if !encrypted {
+        return md5;
+    }
+    let digits: Vec<u8> = bytes.iter().enumerate().map(|(i, &c)| {
+        let value = if i == 9 { c - b'g' } else { (c as char).to_digit(16).unwrap() as u8 };
+        value ^ (i as u8 & 15)
+    }).collect();
+    let hex: String = digits.iter().map(|d| std::char::from_digit(*d as u32, 16).unwrap()).collect();
+    format!("{}{}{}{}", &hex[8..16], &hex[0..8], &hex[24..32], &hex[16..24])
+}
+
+/**
+ * 规范化网盘中的绝对路径: 去掉多余的 / 和 . , 不允许 .. (避免跳出应用目录)
+ * 不是绝对路径或者包含 .. 时返回None
+ */
+pub fn normalize_remote_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => return None,
+            part => parts.push(part),
+        }
+    }
+    Some(format!("/{}", parts.join("/")))
+}
+
+/**
+ * 把本地的相对路径拼接到远程目录后面 (windows下也统一使用 / 分隔)
+ * 文件名不是UTF-8时返回None, 不能替换成U+FFFD上传到网盘
+ */
+pub fn remote_join(remote_dir: &str, relative: &Path) -> Option<String> {
+    let mut path = remote_dir.trim_end_matches('/').to_string();
+    for component in relative.components() {
+        path.push('/');
+        path.push_str(component.as_os_str().to_str()?);
+    }
+    Some(path)
+}
+
+/**
+ * 遍历本地目录的结果, 路径都是相对于root的
+ */
+#[derive(Debug, Default)]
+pub struct LocalTree {
+    pub dirs: Vec<PathBuf>,//所有子目录, 父目录排在子目录前面
+    pub files: Vec<PathBuf>,//所有普通文件
+    pub errors: Vec<(PathBuf, std::io::Error)>,//读取失败的目录/文件, 不影响其他部分
+}
+
+/**
+ * 递归遍历本地目录, 只包含普通文件
+ * 指向文件的符号链接会跟随到目标, 指向目录的符号链接不跟随(避免循环)
+ * 某个子目录读取失败时记录到errors中, 继续遍历其他部分
+ */
+pub fn walk_dir(root: &Path) -> LocalTree {
+    let mut tree = LocalTree::default();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let entries = std::fs::read_dir(root.join(&relative))
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>());
+        let mut entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                tree.errors.push((relative, e));
+                continue;
+            }
+        };
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = relative.join(entry.file_name());
+            let is_symlink = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+            let metadata = match std::fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    tree.errors.push((path, e));
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                if is_symlink {
+                    log::warn!("skipping symlink to directory: {}", root.join(&path).display());
+                    continue;
+                }
+                tree.dirs.push(path.clone());
+                pending.push(path);
+            } else if metadata.is_file() {
+                tree.files.push(path);
+            }
+        }
+    }
+    tree
+}
+
+/**
+ * 写入只有当前用户可读写的文件(凭证/配置可能包含app_secret和token): 先写到临时文件再改名
+ * 临时文件创建时就是0600, 不会有其他用户可读的时间窗口
+ */
+pub fn write_private_file(path: &Path, tmp_path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
+    use std::io::Write;
+    //上次残留的临时文件权限可能不对, 先删除再新建
+    match std::fs::remove_file(tmp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {},
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
+
+/**
+ * 人类可读的大小 如 1.5K 23.0M 4.2G
+ */
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+/**
+ * 把unix时间戳(秒)格式化为 YYYY-MM-DD HH:MM (UTC+8, 和网盘网页端一致)
+ */
+pub fn format_timestamp(secs: u64) -> String {
+    let secs = secs + 8 * 3600;
+    let days = (secs / 86400) as i64;
+    let (hour, minute) = (secs % 86400 / 3600, secs % 3600 / 60);
+    //days since 1970-01-01 -> 年月日 (Howard Hinnant's civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, hour, minute)
+}
+
 pub struct SliceFileInfo<'a> {
     pub file_path: &'a str,//源文件路径
     pub size: u64,//actual size
@@ -53,38 +301,201 @@ impl Clone for SliceFileInfo<'_> {
 }
 
 impl SliceFileInfo<'_> {
-   
-    pub async fn read(&self) -> Result<Vec<u8>, std::io::Error> {
-        let mut file = tokio::fs::File::open(self.file_path).await?;
-        let start = (self.seq as u64) * self.slice_size;
+    /**
+     * 分片内容的stream, 每次最多读取buf_size, 不会把整个分片读进内存
+     */
+    pub fn stream(&self, buf_size: usize) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static {
+        file_range_stream(PathBuf::from(self.file_path), self.seq * self.slice_size, self.size, buf_size)
+    }
+}
 
-        file.seek(tokio::io::SeekFrom::Start(start)).await?;
+/**
+ * 按块读取文件中 [start, start + size) 的内容, 内存中只有一个块 (文件在第一次读取时才打开)
+ * 文件比预期的短时返回UnexpectedEof
+ */
+pub fn file_range_stream(path: PathBuf, start: u64, size: u64, buf_size: usize) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static {
+    struct State {
+        path: PathBuf,
+        file: Option<tokio::fs::File>,
+        start: u64,
+        remaining: u64,
+        buf_size: usize,
+    }
 
-        let mut total_read = 0;
-        let mut buffer = vec![0u8; self.size as usize];
-        while total_read < self.size as usize {
-            //一次读取 未必能全部读完 尤其是比较大的文件 (目前来看我的mac是每次2MB)
-            let bytes_read = file.read(&mut buffer[total_read..]).await?;
-            //println!("bytes_read:{} total_read:{}", bytes_read, total_read);
+    let state = State { path, file: None, start, remaining: size, buf_size: buf_size.max(1) };
+    futures::stream::try_unfold(state, |mut state| async move {
+        if state.remaining == 0 {
+            return Ok(None);
+        }
+        let file = match &mut state.file {
+            Some(file) => file,
+            None => {
+                let mut file = tokio::fs::File::open(&state.path).await?;
+                file.seek(std::io::SeekFrom::Start(state.start)).await?;
+                state.file.insert(file)
+            }
+        };
+        let mut buffer = vec![0u8; (state.remaining.min(state.buf_size as u64)) as usize];
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{:?} ended early, {} bytes missing", state.path, state.remaining),
+            ));
+        }
+        buffer.truncate(bytes_read);
+        state.remaining -= bytes_read as u64;
+        Ok(Some((Bytes::from(buffer), state)))
+    })
+}
 
-            if bytes_read == 0 {
-                break; // 文件结束
+
+/**
+ * 下载时的分段(HTTP Range), 和SliceFileInfo一样由 seq * slice_size 计算起始位置
+ */
+#[derive(Debug, Clone)]
+pub struct RangeInfo {
+    pub seq: u64,
+    pub size: u64,//actual size (最后一段可能小于slice_size)
+    pub slice_size: u64,
+}
+
+impl RangeInfo {
+    pub fn start(&self) -> u64 {
+        self.seq * self.slice_size
+    }
+
+    /** 包含end, 对应 Range: bytes=start-end */
+    pub fn end(&self) -> u64 {
+        self.start() + self.size - 1
+    }
+}
+
+pub fn split_range(total_size: u64, slice_size: u64) -> Vec<RangeInfo> {
+    let chunks = total_size.div_ceil(slice_size);
+    (0..chunks).map(|seq| RangeInfo {
+        seq,
+        size: std::cmp::min(slice_size, total_size - seq * slice_size),
+        slice_size,
+    }).collect()
+}
+
+/**
+ * 根据已知的每个分片md5(比如续传记录中保存的block_list)直接构造分片信息, 不用再读一遍文件计算md5
+ */
+pub fn slices_of<'a>(file_path: &'a str, file_size: u64, slice_size: u64, block_list: &[String]) -> Vec<SliceFileInfo<'a>> {
+    block_list.iter().enumerate().map(|(i, md5)| {
+        let seq = i as u64;
+        let size = std::cmp::min(slice_size, file_size - seq * slice_size);
+        SliceFileInfo { file_path, size, seq, slice_size, md5: md5.clone() }
+    }).collect()
+}
+
+/**
+ * 上传用到的所有md5, 由hash_file一次顺序读取文件得到
+ */
+pub struct FileHashes {
+    pub block_list: Vec<String>,//每个分片的md5, 按seq排列
+    pub content_md5: String,//整个文件的md5
+    pub slice_md5: String,//文件前head_size字节的md5(秒传用), 文件不足head_size时等于content_md5
+}
+
+/** 计算md5时每次读取的大小 */
+const HASH_BUFFER_SIZE: u64 = 1024 * 1024;
+
+/**
+ * 只读一遍文件, 同时计算每个分片的md5、整个文件的md5和前head_size字节的md5
+ * 读文件的线程负责整个文件和开头部分的md5, 分片的md5按seq轮流交给threads个线程计算
+ * 线程之间的队列有长度限制, 计算跟不上时读取会等待, 内存中最多只有几个块
+ */
+pub async fn hash_file(file_path: &str, slice_size: u64, head_size: u64, threads: usize) -> Result<FileHashes, std::io::Error> {
+    let file_path = file_path.to_string();
+    tokio::task::spawn_blocking(move || hash_file_blocking(&file_path, slice_size, head_size, threads))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+fn hash_file_blocking(file_path: &str, slice_size: u64, head_size: u64, threads: usize) -> Result<FileHashes, std::io::Error> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(file_path)?;
+    let total_size = file.metadata()?.len();
+    let slice_size = slice_size.max(1);
+    //空文件也需要一个分片, md5为空内容的md5
+    let slice_count = total_size.div_ceil(slice_size).max(1);
+    let threads = threads.clamp(1, slice_count as usize);
+
+    let mut content = md5::Context::new();
+    let mut head = md5::Context::new();
+    let mut block_list = vec![String::new(); slice_count as usize];
+
+    std::thread::scope(|scope| -> Result<(), std::io::Error> {
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..threads).map(|_| {
+            //(seq, 数据块, 是否是这个分片的最后一块)
+            let (sender, receiver) = std::sync::mpsc::sync_channel::<(u64, Vec<u8>, bool)>(4);
+            let worker = scope.spawn(move || {
+                let mut digests = Vec::new();
+                let mut context = md5::Context::new();
+                //分给同一个线程的分片是按顺序一个接一个读出来的, 不会交错
+                for (seq, chunk, last) in receiver {
+                    context.consume(&chunk);
+                    if last {
+                        let done = std::mem::replace(&mut context, md5::Context::new());
+                        digests.push((seq, format!("{:x}", done.compute())));
+                    }
+                }
+                digests
+            });
+            (sender, worker)
+        }).unzip();
+
+        let mut offset = 0u64;
+        for seq in 0..slice_count {
+            let sender = &senders[(seq % threads as u64) as usize];
+            let mut remaining = std::cmp::min(slice_size, total_size - offset);
+            loop {
+                let len = std::cmp::min(remaining, HASH_BUFFER_SIZE);
+                let mut chunk = vec![0u8; len as usize];
+                //文件在计算过程中被截断时返回UnexpectedEof
+                file.read_exact(&mut chunk)?;
+                content.consume(&chunk);
+                if offset < head_size {
+                    head.consume(&chunk[..std::cmp::min(head_size - offset, len) as usize]);
+                }
+                offset += len;
+                remaining -= len;
+                let last = remaining == 0;
+                //发送失败说明计算线程已经退出, 下面join时会返回错误
+                if sender.send((seq, chunk, last)).is_err() || last {
+                    break;
+                }
             }
-            total_read += bytes_read;
         }
+        drop(senders);
 
-        if total_read != self.size as usize {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("read slice failed on seq:{}, total_read:{} expect:{}", self.seq, total_read, self.size),
-            ))
+        for worker in workers {
+            let digests = worker.join().map_err(|_| std::io::Error::other("md5 worker thread panicked"))?;
+            for (seq, md5) in digests {
+                block_list[seq as usize] = md5;
+            }
         }
-        Ok(buffer)
+        Ok(())
+    })?;
+
+    if block_list.iter().any(|md5| md5.is_empty()) {
+        return Err(std::io::Error::other(format!("failed to compute md5 of all slices: {}", file_path)));
     }
+    let content_md5 = format!("{:x}", content.compute());
+    let slice_md5 = if total_size > head_size { format!("{:x}", head.compute()) } else { content_md5.clone() };
+    Ok(FileHashes { block_list, content_md5, slice_md5 })
 }
 
-
-pub async fn split_file2(file_path: &str,slice_size: u64,) -> Result<Vec<SliceFileInfo>, std::io::Error> {
+/**
+ * 逻辑分割文件, 同时得到秒传需要的md5 (只读一遍文件)
+ * @param head_size 秒传校验段的大小
+ * @param threads 计算分片md5的线程数
+ */
+pub async fn split_file2(file_path: &str, slice_size: u64, head_size: u64, threads: usize) -> Result<(Vec<SliceFileInfo<'_>>, FileHashes), std::io::Error> {
     if !Path::new(file_path).exists() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
@@ -99,25 +510,9 @@ pub async fn split_file2(file_path: &str,slice_size: u64,) -> Result<Vec<SliceFi
         ))
     }
 
-    let total_file_size = metadata.len();
-    let chunks = (total_file_size + slice_size - 1) / slice_size;
-
-    let mut results = Vec::with_capacity(chunks as usize);
-
-    let mut file = tokio::fs::File::open(file_path).await?;
-
-    for i in 0..chunks { 
-
-        let start = i * slice_size;
-        let size = std::cmp::min(slice_size, total_file_size - start);
-
-        let mut buffer = vec![0u8; size as usize];
-        file.read_exact(&mut buffer).await?;
-
-        let md5 = md5_sum_part(file_path, start, size).await?;
-        results.push(SliceFileInfo { file_path, size, seq:i, slice_size, md5 })
-    }
-    Ok(results)
+    let hashes = hash_file(file_path, slice_size, head_size, threads).await?;
+    let slices = slices_of(file_path, metadata.len(), slice_size, &hashes.block_list);
+    Ok((slices, hashes))
 }
 
  /**
@@ -128,6 +523,7 @@ pub async fn split_file2(file_path: &str,slice_size: u64,) -> Result<Vec<SliceFi
  * @param output_dir 输出目录
  * @return 包含每个块路径的向量
  */
+#[allow(dead_code)]
 pub async fn split_file(
     file_path: &str,
     chunk_size: u64,
@@ -150,7 +546,7 @@ pub async fn split_file(
     }
 
     let total_file_size = metadata.len();
-    let chunks = (total_file_size + chunk_size - 1) / chunk_size;
+    let chunks = total_file_size.div_ceil(chunk_size);
 
     let filename = path.file_name().unwrap().to_str().unwrap();
 
@@ -191,3 +587,204 @@ pub async fn split_file(
     Ok(chunk_paths)
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** 测试用的临时文件, 内容是确定的伪随机字节 */
+    fn temp_file(name: &str, size: usize) -> (String, Vec<u8>) {
+        let data: Vec<u8> = (0..size as u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let path = std::env::temp_dir().join(format!("baidu_yunpan_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, &data).unwrap();
+        (path.to_str().unwrap().to_string(), data)
+    }
+
+    fn md5_hex(data: &[u8]) -> String {
+        format!("{:x}", md5::compute(data))
+    }
+
+    /** 和分别计算每一段的md5对比 */
+    async fn check_hash_file(name: &str, size: usize, slice_size: u64, threads: usize) {
+        let (path, data) = temp_file(name, size);
+        let hashes = hash_file(&path, slice_size, 256 * 1024, threads).await.unwrap();
+        let expected: Vec<String> = if data.is_empty() {
+            vec![md5_hex(&[])]
+        } else {
+            data.chunks(slice_size as usize).map(md5_hex).collect()
+        };
+        assert_eq!(hashes.block_list, expected);
+        assert_eq!(hashes.content_md5, md5_hex(&data));
+        assert_eq!(hashes.slice_md5, md5_hex(&data[..data.len().min(256 * 1024)]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hash_file_empty() {
+        check_hash_file("empty", 0, 1, 4).await;
+        check_hash_file("empty_big_slice", 0, 4 * 1024 * 1024, 1).await;
+    }
+
+    #[tokio::test]
+    async fn hash_file_smaller_than_head() {
+        check_hash_file("small", 1000, 1000, 2).await;
+        check_hash_file("head", 256 * 1024, 256 * 1024, 1).await;
+    }
+
+    #[tokio::test]
+    async fn hash_file_exact_multiple_of_slice() {
+        check_hash_file("exact", 3 * 512 * 1024, 512 * 1024, 2).await;
+        check_hash_file("exact_mb", 2 * 1024 * 1024 + 2 * 1024 * 1024, 2 * 1024 * 1024, 1).await;
+    }
+
+    #[tokio::test]
+    async fn hash_file_more_threads_than_slices() {
+        check_hash_file("threads", 1536 * 1024 + 7, 1024 * 1024, 8).await;
+    }
+
+    #[tokio::test]
+    async fn hash_file_missing() {
+        assert!(hash_file("/nonexistent/baidu_yunpan_test", 1024, 256 * 1024, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn md5_sum_part_stops_at_size() {
+        let (path, data) = temp_file("part", 3 * 1024 * 1024);
+        assert_eq!(md5_sum_part(&path, 100, 256 * 1024).await.unwrap(), md5_hex(&data[100..100 + 256 * 1024]));
+        assert_eq!(md5_sum(&path).await.unwrap(), md5_hex(&data));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_private_file_is_0600() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("baidu_yunpan_test_{}_private", std::process::id()));
+        let tmp_path = path.with_extension("tmp");
+        //残留的临时文件权限是0644
+        std::fs::write(&tmp_path, b"stale").unwrap();
+        write_private_file(&path, &tmp_path, b"secret").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!tmp_path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_dir_skips_symlink_loops() {
+        let root = std::env::temp_dir().join(format!("baidu_yunpan_test_{}_walk", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::write(root.join("a/b/file.txt"), b"1").unwrap();
+        std::fs::write(root.join("top.txt"), b"2").unwrap();
+        //指向上级目录的符号链接会形成循环
+        std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("top.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("broken")).unwrap();
+
+        let tree = walk_dir(&root);
+        assert_eq!(tree.dirs, vec![PathBuf::from("a"), PathBuf::from("a/b")]);
+        let mut files = tree.files.clone();
+        files.sort();
+        assert_eq!(files, vec![PathBuf::from("a/b/file.txt"), PathBuf::from("link.txt"), PathBuf::from("top.txt")]);
+        //悬空的符号链接记录为错误, 不影响其他文件
+        let errors: Vec<&PathBuf> = tree.errors.iter().map(|(path, _)| path).collect();
+        assert_eq!(errors, vec![&PathBuf::from("broken")]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /** decode_server_md5的逆运算, 只用于测试 */
+    fn encode_server_md5(md5: &str) -> String {
+        let swapped = format!("{}{}{}{}", &md5[8..16], &md5[0..8], &md5[24..32], &md5[16..24]);
+        swapped.chars().enumerate().map(|(i, c)| {
+            let value = c.to_digit(16).unwrap() as u8 ^ (i as u8 & 15);
+            if i == 9 { (b'g' + value) as char } else { std::char::from_digit(value as u32, 16).unwrap() }
+        }).collect()
+    }
+
+    #[test]
+    fn decode_server_md5_cases() {
+        let md5 = "d41d8cd98f00b204e9800998ecf8427e";
+        //普通的md5原样返回
+        assert_eq!(decode_server_md5(md5), md5);
+        assert_eq!(decode_server_md5("D41D8CD98F00B204E9800998ECF8427E"), md5);
+        let encoded = encode_server_md5(md5);
+        assert!(!encoded.as_bytes()[9].is_ascii_hexdigit());
+        assert_eq!(decode_server_md5(&encoded), md5);
+        //长度不对或者有其他字符时不处理
+        assert_eq!(decode_server_md5("abc"), "abc");
+        assert_eq!(decode_server_md5("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz"), "zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz");
+    }
+
+    #[test]
+    fn normalize_remote_path_cases() {
+        assert_eq!(normalize_remote_path("/apps/test"), Some("/apps/test".to_string()));
+        assert_eq!(normalize_remote_path("//apps/./test//a/"), Some("/apps/test/a".to_string()));
+        assert_eq!(normalize_remote_path("/"), Some("/".to_string()));
+        assert_eq!(normalize_remote_path("apps/test"), None);
+        assert_eq!(normalize_remote_path(""), None);
+        assert_eq!(normalize_remote_path("/apps/../etc"), None);
+    }
+
+    #[test]
+    fn remote_join_uses_slash() {
+        assert_eq!(remote_join("/apps/test/", Path::new("a/b.txt")), Some("/apps/test/a/b.txt".to_string()));
+        assert_eq!(remote_join("/apps/test", Path::new("")), Some("/apps/test".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remote_join_rejects_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let name = std::ffi::OsStr::from_bytes(b"a/\xff.txt");
+        assert_eq!(remote_join("/apps/test", Path::new(name)), None);
+    }
+
+    #[test]
+    fn split_range_last_range_is_shorter() {
+        let ranges = split_range(10, 4);
+        let bounds: Vec<(u64, u64)> = ranges.iter().map(|r| (r.start(), r.end())).collect();
+        assert_eq!(bounds, vec![(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(split_range(8, 4).len(), 2);
+        assert!(split_range(0, 4).is_empty());
+    }
+
+    #[test]
+    fn slices_of_matches_block_list() {
+        let block_list = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let slices = slices_of("f", 10, 4, &block_list);
+        let sizes: Vec<(u64, u64, &str)> = slices.iter().map(|s| (s.seq, s.size, s.md5.as_str())).collect();
+        assert_eq!(sizes, vec![(0, 4, "a"), (1, 4, "b"), (2, 2, "c")]);
+        //空文件只有一个大小为0的分片
+        let slices = slices_of("f", 0, 1, &block_list[..1]);
+        assert_eq!(slices[0].size, 0);
+    }
+
+    #[test]
+    fn format_timestamp_is_utc8() {
+        assert_eq!(format_timestamp(0), "1970-01-01 08:00");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-15 06:13");
+        //2000-02-29 00:00 UTC 闰日
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 08:00");
+        //跨年: 2023-12-31 16:00 UTC 是UTC+8的元旦
+        assert_eq!(format_timestamp(1_704_038_400), "2024-01-01 00:00");
+    }
+
+    #[test]
+    fn human_size_units() {
+        assert_eq!(human_size(1023), "1023");
+        assert_eq!(human_size(1536), "1.5K");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0G");
+    }
+
+    #[test]
+    fn retry_delay_is_bounded() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..20 {
+            let delay = policy.delay(attempt);
+            let backoff = policy.base_delay.saturating_mul(1 << (attempt - 1).min(16)).min(policy.max_delay);
+            assert!(delay >= backoff / 2 && delay <= backoff, "attempt {} delay {:?}", attempt, delay);
+        }
+    }
+}
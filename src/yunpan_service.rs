@@ -1,13 +1,21 @@
 
+use futures::{StreamExt, TryStreamExt};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use url::Url;
-use std::collections::hash_set;
+use std::collections::{hash_set, BTreeMap};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::task::JoinSet;
-// use tokio::io::AsyncWriteExt; 
-use crate::utils::{split_file,split_file2, md5_sum ,SliceFileInfo};
+use crate::utils::{split_file,split_file2, slices_of, split_range, hash_file, md5_sum, normalize_remote_path, decode_server_md5, remote_join, walk_dir, file_range_stream, LocalTree, human_size, format_timestamp, RangeInfo, FileHashes, RateLimiter, RetryPolicy, SliceFileInfo};
+use crate::upload_journal::UploadJournal;
+use crate::download_journal::DownloadJournal;
+use crate::auth::TokenProvider;
+use crate::progress::{Progress, ProgressMode};
+use bytes::Bytes;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 // 定义自定义错误类型
 #[derive(Debug)]
@@ -16,6 +24,90 @@ pub enum YunPanError {
     Reqwest(reqwest::Error),
     Serde(serde_json::Error),
     Biz(String),
+    InvalidArgument(String),//命令行参数或配置的值不合法, 和clap的参数错误一样退出码为2
+    AuthExpired(String),//access_token过期或无效 errno=-6 error_code=110/111
+    QuotaExceeded(String),//网盘容量不足 errno=-10
+    InvalidPath(String),//文件名错误或者没有权限 errno=-7
+    NotFound(String),//文件或目录不存在 errno=-9
+    FileExists(String),//文件或目录已存在 errno=-8
+    RateLimited(String),//请求太频繁 errno=31034 error_code=4/17/18 HTTP 429
+    ServerBusy(String),//服务端暂时不可用, 请求没有被处理 error_code=1/2 HTTP 503
+    ServerError(String),//服务端出错, 不确定请求是否已经被处理 HTTP 500/502/504等
+    Api { errno: i64, message: String },//接口返回的其他错误码
+}
+
+impl YunPanError {
+    /**
+     * 根据接口返回的errno(xpan接口)/error_code(openapi网关层和pcs上传接口)转换成对应的错误
+     * 同一个数字在两种错误码中的意思不一样(如errno=111是有其他异步任务在执行, error_code=111是token过期), 所以分开判断
+     */
+    fn from_api(errno: Option<i64>, error_code: Option<i64>, message: String) -> YunPanError {
+        match (errno, error_code) {
+            (_, Some(110 | 111)) | (Some(-6), _) => YunPanError::AuthExpired(message),
+            (_, Some(4 | 17 | 18 | 31034)) | (Some(31034), _) => YunPanError::RateLimited(message),
+            (_, Some(1 | 2)) => YunPanError::ServerBusy(message),
+            (Some(-10), _) => YunPanError::QuotaExceeded(message),
+            (Some(-7), _) => YunPanError::InvalidPath(message),
+            (Some(-9 | 31066), _) => YunPanError::NotFound(message),
+            (Some(-8 | 31061), _) => YunPanError::FileExists(message),
+            (_, Some(code)) if code != 0 => YunPanError::Api { errno: code, message },
+            (Some(errno), _) => YunPanError::Api { errno, message },
+            (None, _) => YunPanError::Biz(message),
+        }
+    }
+
+    /**
+     * HTTP状态码不是成功时的错误
+     */
+    fn from_status(status: reqwest::StatusCode, message: String) -> YunPanError {
+        match status.as_u16() {
+            401 => YunPanError::AuthExpired(message),
+            429 => YunPanError::RateLimited(message),
+            503 => YunPanError::ServerBusy(message),
+            500..=599 => YunPanError::ServerError(message),
+            _ => YunPanError::Biz(format!("{} status:{}", message, status)),
+        }
+    }
+
+    /**
+     * 是否是临时性的错误, 稍后重试可能成功
+     */
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            YunPanError::RateLimited(_) | YunPanError::ServerBusy(_) | YunPanError::ServerError(_) => true,
+            YunPanError::Reqwest(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            _ => false,
+        }
+    }
+
+    /**
+     * 是否可以确定请求没有被服务端处理, 非幂等的请求(重命名策略的创建/复制等)只有这种情况才能重试
+     * 超时等错误时服务端可能已经处理过了, 重试会多出一个重命名的文件
+     */
+    pub fn is_retryable_unprocessed(&self) -> bool {
+        match self {
+            YunPanError::RateLimited(_) | YunPanError::ServerBusy(_) => true,
+            YunPanError::Reqwest(e) => e.is_connect(),
+            _ => false,
+        }
+    }
+
+    /**
+     * 进程的退出码, 方便脚本区分错误类型 (2为命令行参数错误, clap和InvalidArgument使用)
+     */
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            YunPanError::InvalidArgument(_) => 2,
+            YunPanError::AuthExpired(_) => 3,
+            YunPanError::QuotaExceeded(_) => 4,
+            YunPanError::InvalidPath(_) | YunPanError::NotFound(_) => 5,
+            YunPanError::FileExists(_) => 6,
+            YunPanError::RateLimited(_) => 7,
+            YunPanError::ServerBusy(_) | YunPanError::ServerError(_) | YunPanError::Reqwest(_) => 8,
+            YunPanError::Io(_) => 9,
+            YunPanError::Serde(_) | YunPanError::Biz(_) | YunPanError::Api { .. } => 1,
+        }
+    }
 }
 impl From<std::io::Error> for YunPanError {
     fn from(err: std::io::Error) -> Self {
@@ -27,30 +119,273 @@ impl From<reqwest::Error> for YunPanError {
         YunPanError::Reqwest(err)
     }
 }
+impl std::fmt::Display for YunPanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            YunPanError::Reqwest(err) => write!(f, "Network error: {}", err),
+            YunPanError::Io(err) => write!(f, "Io error: {}", err),
+            YunPanError::Biz(err) => write!(f, "Error: {}", err),
+            YunPanError::InvalidArgument(err) => write!(f, "Invalid argument: {}", err),
+            YunPanError::Serde(err) => write!(f, "Unexpected response: {}", err),
+            YunPanError::AuthExpired(err) => write!(f, "Access token expired or invalid, please run login again: {}", err),
+            YunPanError::QuotaExceeded(err) => write!(f, "Not enough space in the netdisk: {}", err),
+            YunPanError::InvalidPath(err) => write!(f, "Invalid path or no permission: {}", err),
+            YunPanError::NotFound(err) => write!(f, "Not found: {}", err),
+            YunPanError::FileExists(err) => write!(f, "Already exists: {}", err),
+            YunPanError::RateLimited(err) => write!(f, "Too many requests: {}", err),
+            YunPanError::ServerBusy(err) => write!(f, "Server is busy: {}", err),
+            YunPanError::ServerError(err) => write!(f, "Server error: {}", err),
+            YunPanError::Api { errno, message } => write!(f, "Api error errno:{}: {}", errno, message),
+        }
+    }
+}
+
+/**
+ * 远程路径已存在时的处理方式, 对应接口中的rtype
+ */
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    Fail,//0 不重命名, 返回冲突
+    Rename,//1 只要path冲突即重命名
+    RenameIfDifferent,//2 path冲突且block_list不同才重命名
+    #[default]
+    Overwrite,//3 覆盖
+}
+
+impl ConflictPolicy {
+    /**
+     * 同一个请求执行多次的结果是否一样: 覆盖是, 重命名会多出文件, 失败策略第二次会返回已存在
+     */
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, ConflictPolicy::Overwrite)
+    }
+
+    pub fn rtype(&self) -> u32 {
+        match self {
+            ConflictPolicy::Fail => 0,
+            ConflictPolicy::Rename => 1,
+            ConflictPolicy::RenameIfDifferent => 2,
+            ConflictPolicy::Overwrite => 3,
+        }
+    }
+
+    /**
+     * 文件管理接口中的ondup, 复制/移动时不比较文件内容, rename-if-different 和 rename 一样处理
+     */
+    pub fn ondup(&self) -> &'static str {
+        match self {
+            ConflictPolicy::Fail => "fail",
+            ConflictPolicy::Rename | ConflictPolicy::RenameIfDifferent => "newcopy",
+            ConflictPolicy::Overwrite => "overwrite",
+        }
+    }
+}
+
+/**
+ * 文件管理接口的操作类型, 对应opera参数
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOperation {
+    Copy,
+    Move,
+    Rename,
+    Delete,
+}
+
+impl FileOperation {
+    fn as_param(&self) -> &'static str {
+        match self {
+            FileOperation::Copy => "copy",
+            FileOperation::Move => "move",
+            FileOperation::Rename => "rename",
+            FileOperation::Delete => "delete",
+        }
+    }
+}
+
+/**
+ * 列目录时的排序字段, 对应list接口的order参数
+ */
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListOrder {
+    #[default]
+    Name,//文件名
+    Time,//修改时间
+    Size,//文件大小 (目录总是排在前面)
+}
+
+impl ListOrder {
+    fn as_param(&self) -> &'static str {
+        match self {
+            ListOrder::Name => "name",
+            ListOrder::Time => "time",
+            ListOrder::Size => "size",
+        }
+    }
+}
+
+/**
+ * 文件的分类 对应RemoteFile::category
+ */
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileCategory {
+    Video,//1
+    Audio,//2
+    Image,//3
+    Doc,//4
+    App,//5
+    Other,//6
+    Torrent,//7
+}
+
+impl FileCategory {
+    pub fn code(&self) -> u32 {
+        match self {
+            FileCategory::Video => 1,
+            FileCategory::Audio => 2,
+            FileCategory::Image => 3,
+            FileCategory::Doc => 4,
+            FileCategory::App => 5,
+            FileCategory::Other => 6,
+            FileCategory::Torrent => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListOptions {
+    pub recursive: bool,//递归列出所有子目录 (使用listall接口)
+    pub order: ListOrder,
+    pub desc: bool,//降序
+    pub dirs_only: bool,//只列出目录
+}
 
 pub struct CliUploadRequest {
     file_path: String,
     chunk_size: u64,
-    // continues: bool,
-    // dir: Option<String>,
+    continues: bool,//是否续传(复用本地记录的upload_id)
+    parallel: usize,//同时上传的分片数
+    dir: Option<String>,//上传到的远程目录(文件名不变), 相对路径时基于app_root
+    remote_path: Option<String>,//上传到的远程完整路径, 和dir只能指定一个
+    on_conflict: ConflictPolicy,
+    check_free_space: bool,//上传前检查剩余空间, 目录上传时已经统一检查过了
 }
 impl CliUploadRequest  {
-    pub fn new(file_path: &str, chunk_size: u64) -> Self {
-        if chunk_size < 4 * 1024 * 1024 {
-            panic!("chunk_size must be greater than or equal to 4MB");
-        }
+    pub fn new(
+        file_path: &str,
+        chunk_size: u64,
+        continues: bool,
+        parallel: usize,
+        dir: Option<String>,
+        remote_path: Option<String>,
+        on_conflict: ConflictPolicy,
+    ) -> Self {
+        //chunk_size由调用方校验(至少4MB), 见main中的chunk_size
         CliUploadRequest { 
             file_path: file_path.to_string(),
             chunk_size,
-            // continues,
-            // dir: None
+            continues,
+            parallel: parallel.max(1),
+            dir,
+            remote_path,
+            on_conflict,
+            check_free_space: true,
+        }
+    }
+}
+
+pub struct CliDownloadRequest {
+    remote_path: String,
+    local_path: Option<String>,//本地保存路径 默认为当前目录下的同名文件,指定目录时保存到该目录下
+    chunk_size: u64,//每个Range请求的大小
+    parallel: usize,//同时下载的连接数
+    verify: bool,//下载完成后校验md5
+}
+impl CliDownloadRequest {
+    pub fn new(remote_path: &str, local_path: Option<String>, chunk_size: u64, parallel: usize, verify: bool) -> Self {
+        CliDownloadRequest {
+            remote_path: remote_path.to_string(),
+            local_path,
+            chunk_size: chunk_size.max(1),
+            parallel: parallel.max(1),
+            verify,
         }
     }
 }
+
+#[derive(Debug, Serialize)]
+pub struct CliDownloadResponse {
+    remote_path: String,
+    local_path: PathBuf,
+    size: u64,
+    md5: Option<String>,
+}
+impl std::fmt::Display for CliDownloadResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} size:{}", self.remote_path, self.local_path.display(), self.size)
+    }
+}
  
+/** 没有配置时默认的应用目录, 普通应用只能上传到 /apps/<应用名> 下 */
+pub const DEFAULT_APP_ROOT: &str = "/apps/asitanokibou";
+
+//各个接口的地址, 具体的接口由method参数区分 (quota和taskquery没有method)
+const XPAN_FILE_URL: &str = "https://pan.baidu.com/rest/2.0/xpan/file";
+const XPAN_MULTIMEDIA_URL: &str = "https://pan.baidu.com/rest/2.0/xpan/multimedia";
+const XPAN_NAS_URL: &str = "https://pan.baidu.com/rest/2.0/xpan/nas";
+const PCS_SUPERFILE2_URL: &str = "https://c.pcs.baidu.com/rest/2.0/pcs/superfile2";
+const QUOTA_URL: &str = "https://pan.baidu.com/api/quota";
+const TASK_QUERY_URL: &str = "https://pan.baidu.com/share/taskquery";
+
+/**
+ * 创建YunPanService的可选项 (来自配置文件的profile和命令行)
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ServiceOptions {
+    pub app_root: Option<String>,
+    pub proxy: Option<String>,
+    pub upload_limit: Option<u64>,//上传限速 bytes/s
+    pub download_limit: Option<u64>,//下载限速 bytes/s
+    pub max_attempts: Option<u32>,//临时性错误最多尝试的次数
+    pub progress: ProgressMode,//上传/下载进度的显示方式
+}
+
 pub struct YunPanService {
-    access_token: String,
+    tokens: TokenProvider,
+    app_root: String,//应用目录 相对路径都基于这个目录, 上传的文件必须在这个目录下
     client: Client,
+    upload_limiter: Option<Arc<RateLimiter>>,//上传的请求体是'static的stream, 所以用Arc共享
+    download_limiter: Option<RateLimiter>,
+    retry: RetryPolicy,
+    progress_mode: ProgressMode,
+}
+
+/** 分片从磁盘按这个大小分块读取并发送, 每发出一块计入一次进度 (内存占用和分片大小无关) */
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/**
+ * 分片上传的请求体: 边读边发, 发出的字节计入进度和sent(失败时用来撤回进度), 有限速时每块发送前先预约
+ */
+fn counting_body<S>(stream: S, limiter: Option<Arc<RateLimiter>>, progress: Progress, sent: Arc<AtomicU64>) -> reqwest::Body
+where
+    S: futures::Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static,
+{
+    let stream = stream.and_then(move |chunk| {
+        let limiter = limiter.clone();
+        let progress = progress.clone();
+        let sent = sent.clone();
+        async move {
+            let len = chunk.len() as u64;
+            if let Some(limiter) = limiter {
+                limiter.acquire(len).await;
+            }
+            progress.inc(len);
+            sent.fetch_add(len, Ordering::Relaxed);
+            Ok(chunk)
+        }
+    });
+    reqwest::Body::wrap_stream(stream)
 }
 
 struct UploadFile {
@@ -58,6 +393,7 @@ struct UploadFile {
     file_name: String,
     // file_md5: String,
     file_size: u64,
+    mtime: u64,//修改时间(秒) 续传时用来判断文件是否有变化
 }
 impl UploadFile {
     pub async fn new(file_path: &str) -> Result<UploadFile, std::io::Error> {
@@ -65,6 +401,10 @@ impl UploadFile {
 
         let file_size = metadata.len();
         let file_name = Path::new(file_path).file_name().unwrap().to_str().unwrap().to_string();
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
 
         Ok(UploadFile {
             file_path: file_path.to_string(),
@@ -72,29 +412,31 @@ impl UploadFile {
             file_name,
             // file_md5,
             file_size,
+            mtime,
         })
     } 
 
     /**
-     * 逻辑分割文件 
-     * @param slice_size 分割文件的大小 注意要大于4MB(严格来说第一个分片要大于等于4MB,小于4MB的直接一次就上传)
-     * @return 分割文件的路径
+     * 实际使用的分片大小, 小于4MB的文件直接一次上传 (空文件也至少为1, 避免除0)
      */
-    pub async fn split2(&self,slice_size: u64) -> Result<Vec<SliceFileInfo>, std::io::Error> {
-        let mut slice_size = slice_size;
-
+    fn slice_size(&self, slice_size: u64) -> u64 {
         if self.file_size <= 4 * 1024 * 1024 {
-            slice_size = self.file_size
+            self.file_size.max(1)
+        } else {
+            slice_size
         }
+    }
 
-        let chunk_files = match split_file2(&self.file_path, slice_size).await {
-            Ok(chunk_files) => chunk_files,
-            Err(e) => {
-                return Err(e);
-            }
-        };
-  
-        Ok(chunk_files)
+    /**
+     * 逻辑分割文件, 同时计算分片md5和秒传需要的md5 (只读一遍文件)
+     * @param slice_size 分割文件的大小 注意要大于4MB(严格来说第一个分片要大于等于4MB,小于4MB的直接一次就上传)
+     * @return 分片信息和文件的md5
+     */
+    pub async fn split2(&self,slice_size: u64) -> Result<(Vec<SliceFileInfo<'_>>, FileHashes), std::io::Error> {
+        let slice_size = self.slice_size(slice_size);
+        //分片的md5可以在多个线程上并行计算
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        split_file2(&self.file_path, slice_size, RAPID_UPLOAD_SLICE_SIZE, threads).await
     }
 
     /**
@@ -104,6 +446,7 @@ impl UploadFile {
      * @param dir 分割文件的保存目录
      * @return 分割文件的路径
     */
+    #[allow(dead_code)]
     pub async fn split(&self,chunk_size: u64) -> Result<Vec<SliceFile>, std::io::Error> {
         let chunk_paths = if self.file_size <= 4 * 1024 * 1024 {
             vec![PathBuf::from(self.file_path.clone())]
@@ -122,7 +465,7 @@ impl UploadFile {
             let file_path = chunk_path.to_str().unwrap().to_string();
             set.spawn(async move {
                 let md5 = md5_sum(&file_path).await.unwrap();
-                println!("index: {} md5: {}",index, md5);
+                log::debug!("index: {} md5: {}",index, md5);
                 SliceFile { seq:index,file_path,md5,}
             });
         } 
@@ -131,7 +474,7 @@ impl UploadFile {
             let sf = match res {
                 Ok(slice_file) =>  slice_file,
                 Err(e) => {
-                    eprintln!("Error: {:?}", e);
+                    log::error!("Error: {:?}", e);
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::NotFound,"")  //这里应该是其他错误类型，但是为了方便测试，这里直接返回NotFound
                     );
@@ -144,6 +487,7 @@ impl UploadFile {
     }
 }
 
+#[allow(dead_code)]
 struct SliceFile {
     seq: usize,
     file_path: String,//slice file path
@@ -166,6 +510,7 @@ impl XPanFilePreCreateRequest {
         path: &str,
         size: u64,
         block_list: &Vec<String>,//每个block的 md5
+        rtype: u32,
     ) -> Self {
         //转换成json array 并覆盖掉block_list变量
         let block_list = serde_json::to_string(block_list).unwrap();
@@ -174,21 +519,23 @@ impl XPanFilePreCreateRequest {
             path : path.to_string(),
             size,
             block_list ,
-            isdir: 0,//暂时不支持目录上传
+            isdir: 0,//目录不需要预上传 直接通过create创建(见create_dir)
             autoinit: 1,
-            rtype: 3,//统一覆盖
+            rtype,
             uploadid: None,
         }
     }
 }
 #[derive(Debug, Serialize, Deserialize)]
 struct XPanPrecreateResponse {
-    errno: Option<u32>, //错误码 0：表示成功, -7:文件或目录名错误或无权访问,-10:容量不足..
+    errno: Option<i32>, //错误码 0：表示成功, -7:文件或目录名错误或无权访问,-10:容量不足..
     request_id: u64,
-    #[serde(rename = "uploadid")]
+    #[serde(rename = "uploadid", default)]
     upload_id: String,
-    return_type: u32,
+    return_type: u32,//1 文件在云端不存在, 2 文件在云端已存在(此时没有uploadid,block_list)
+    #[serde(default)]
     block_list: Vec<u32>,//注意返回的不是和请求的一样，需要上传的分片序号列表，索引从0开始
+    info: Option<RemoteFile>,//return_type=2时返回已存在的文件信息
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -212,6 +559,7 @@ impl XPanFileCreateRequest {
         size: u64,
         block_list: &Vec<String>,
         upload_id: &str,
+        rtype: u32,
     ) -> Self {
         //转换成json array 并覆盖掉block_list变量
         let block_list = serde_json::to_string(block_list).unwrap();
@@ -220,246 +568,1690 @@ impl XPanFileCreateRequest {
             path: path.to_string(),
             size,
             isdir: 0,
-            rtype: 3,//统一覆盖
+            rtype,
             block_list ,
             uploadid: upload_id.to_string()
         }
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct XPanCreateResponse {
-    errno: Option<u32>, //错误码 0：表示成功 
-    fs_id: u64, //文件id
-    md5: String, //文件的MD5，只有提交文件时才返回，提交目录时没有该值
-    category: u32, //分类类型, 1 视频 2 音频 3 图片 4 文档 5 应用 6 其他 7 种子
-    server_filename: Option<String>, //服务器文件名 -居然和文档不一样（不返回） 仅返回name而且和path一样
-    path: String, //上传后使用的文件绝对路径
-    size: u64, //文件大小
-    ctime: u64, //创建时间
-    mtime: u64, //修改时间
-    isdir: u8, //是否为目录 0:为文件 1:为目录
+    //创建目录 不需要block_list和uploadid
+    pub fn new_dir(path: &str, rtype: u32) -> Self {
+        XPanFileCreateRequest {
+            path: path.to_string(),
+            size: 0,
+            isdir: 1,
+            rtype,
+            block_list: "[]".to_string(),
+            uploadid: String::new(),
+        }
+    }
 }
 
+//只解析错误码, 失败时的响应里没有其他字段
 #[derive(Debug, Serialize, Deserialize)]
-struct XPanUploadResponse { 
-    md5: String, //文件切片云端md5
-    error_code: Option<u32>, //错误码  None为成功!(tmd api都不同格式的) 
-    error_msg: Option<String>,
+struct XPanErrnoResponse {
+    errno: Option<i64>,
+    error_code: Option<i64>, //openapi网关层和pcs上传接口的错误 如 110:token无效 111:token过期
 }
 
-impl YunPanService {
-    pub fn new(access_token: String) -> Self {
-        let client = Client::builder()
-            .connect_timeout(Duration::from_secs(10))
-            .timeout(Duration::from_secs(30))
-            .build()
-            .expect("Failed to build HTTP client");
+/**
+ * 读取响应内容, 错误码不为0(allowed中的除外)或者HTTP状态不是成功时返回对应的错误
+ * 各个接口的格式不一样: xpan接口为errno, 网关层和pcs接口为error_code
+ */
+async fn read_response(response: reqwest::Response, context: &str, allowed: &[i64]) -> Result<String, YunPanError> {
+    let status = response.status();
+    let raw_response_text = response.text().await?;
+    check_response(status, &raw_response_text, context, allowed)?;
+    Ok(raw_response_text)
+}
 
-        YunPanService {
-            access_token,
-            client,
+/** read_response中检查错误码和HTTP状态的部分 */
+fn check_response(status: reqwest::StatusCode, raw_response_text: &str, context: &str, allowed: &[i64]) -> Result<(), YunPanError> {
+    if let Ok(XPanErrnoResponse { errno, error_code }) = serde_json::from_str::<XPanErrnoResponse>(raw_response_text) {
+        let errno = errno.filter(|errno| *errno != 0 && !allowed.contains(errno));
+        let error_code = error_code.filter(|code| *code != 0);
+        if errno.is_some() || error_code.is_some() {
+            return Err(YunPanError::from_api(errno, error_code, format!("{} {:?}", context, raw_response_text)));
         }
     }
+    if !status.is_success() {
+        return Err(YunPanError::from_status(status, format!("{} {:?}", context, raw_response_text)));
+    }
+    Ok(())
+}
 
-    //预上传 doc : https://pan.baidu.com/union/doc/3ksg0s9r7
-    async fn precreate(
-        &self,
-        request: &XPanFilePreCreateRequest,
-    ) -> Result<XPanPrecreateResponse, YunPanError> {
-        let url = format!(
-            "https://pan.baidu.com/rest/2.0/xpan/file?method=precreate&access_token={}",
-            self.access_token
-        );
+/** 解析read_response返回的内容, 失败时记录原始响应 */
+fn parse_response<T: serde::de::DeserializeOwned>(raw_response_text: &str, context: &str) -> Result<T, YunPanError> {
+    serde_json::from_str::<T>(raw_response_text).map_err(|e| {
+        log::error!("serde_json::from_str failed on {} response: {:?}", context, raw_response_text);
+        YunPanError::Serde(e)//解析错误
+    })
+}
 
-        let response: reqwest::Response = self.client.post(&url).form(request).send().await?;
-    
-        let raw_response_text = response.text().await?; // 直接转json response.json::<XPanPrecreateResponse>()
+/**
+ * 网盘中的一个文件或目录, list/listall/filemetas/create等接口返回的文件信息都用这个结构
+ * 各个接口返回的字段不完全一样, 没有返回的字段为默认值
+ * doc: https://pan.baidu.com/union/doc/nksg0sat9 https://pan.baidu.com/union/doc/Fksg0sbcm
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoteFile {
+    fs_id: u64, //文件id
+    path: String, //文件的绝对路径
+    #[serde(default)]
+    server_filename: String, //文件名 create接口不返回
+    #[serde(default)]
+    size: u64, //文件大小 目录为0
+    #[serde(default)]
+    isdir: u8, //是否为目录 0:为文件 1:为目录
+    #[serde(default)]
+    md5: Option<String>, //目录没有md5
+    #[serde(default)]
+    category: u32, //分类类型, 1 视频 2 音频 3 图片 4 文档 5 应用 6 其他 7 种子
+    #[serde(default, alias = "ctime")]
+    server_ctime: u64, //服务器端的创建时间 create接口中为ctime
+    #[serde(default, alias = "mtime")]
+    server_mtime: u64, //服务器端的修改时间 create接口中为mtime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dlink: Option<String>, //filemetas 指定dlink=1时才返回, 有效期8小时
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbs: Option<BTreeMap<String, String>>, //filemetas 指定thumb=1时返回的缩略图地址 (只有图片有)
+    #[serde(flatten, skip_serializing_if = "BTreeMap::is_empty")]
+    extra: BTreeMap<String, serde_json::Value>, //接口返回的其他字段 如filemetas指定extra=1时的拍摄时间/分辨率等
+}
 
-        //let text: String = response.text().await.expect("failed to get response text");
-        match serde_json::from_str::<XPanPrecreateResponse>(&raw_response_text) {
-            Ok(response) => {
-                return if let Some(0) = response.errno {
-                    Ok(response)
-                }else {
-                    Err(YunPanError::Biz(format!("precreate failed: {:?}", raw_response_text)))
-                }
-            },
-            Err(e) => {
-                eprintln!("serde_json::from_str failed on precreate response: {:?}",raw_response_text);
-                return Err(YunPanError::Serde(e))//解析错误
-            }
+impl RemoteFile {
+    fn category_name(&self) -> &'static str {
+        match self.category {
+            1 => "video",
+            2 => "audio",
+            3 => "image",
+            4 => "doc",
+            5 => "app",
+            7 => "torrent",
+            _ => "other",
         }
     }
+}
 
-
-    //doc : https://pan.baidu.com/union/doc/nksg0s9vi
-    async fn upload_slice(
-        &self,
-        path: &str,
-        upload_id: &str,
-        slice_file: &SliceFile,
-    ) -> Result<XPanUploadResponse, YunPanError> {
-     
-        let mut url = Url::parse("https://c.pcs.baidu.com/rest/2.0/pcs/superfile2").unwrap();
-        url.query_pairs_mut()
-            .append_pair("method", "upload")
-            .append_pair("access_token", &self.access_token)
-            .append_pair("type", "tmpfile")
-            .append_pair("path", path)
-            .append_pair("uploadid", upload_id)
-            .append_pair("partseq", &slice_file.seq.to_string());
- 
-        let file = tokio::fs::read(&slice_file.file_path).await?;
-
-        let file_part  = reqwest::multipart::Part::bytes(file).file_name("filename");//必须指定file_name
-        let form = reqwest::multipart::Form::new().part("file", file_part);
-
-        let response = self.client.post(url).multipart(form).send().await?;
-
-        //let response_body: XPanUploadResponse = response.json().await?;
-        let raw_response_text = response.text().await?; 
-
-        match serde_json::from_str::<XPanUploadResponse>(&raw_response_text) {
-            Ok(response) => {
-                return if let Some(0) = response.error_code.or_else(|| Some(0)) {//没有的话! 默认为0,有md5,request_id就行
-                    Ok(response)
-                } else{
-                    Err(YunPanError::Biz(format!("upload slice failed on seq:{}, {:?}",slice_file.seq,raw_response_text)))
-                }
-            },
-            Err(e) => {
-                eprintln!("serde_json::from_str failed on upload_slice response: {:?}",raw_response_text);
-                return Err(YunPanError::Serde(e))//解析错误
-            }
-        };
+impl std::fmt::Display for RemoteFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} size:{} fs_id:{} md5:{}", self.path, self.size, self.fs_id, self.md5.as_deref().unwrap_or("-"))
     }
-    async fn upload_slice2(
-        &self,
-        path: &str,
-        upload_id: &str,
-        slice_file: &SliceFileInfo<'_>,
-    ) -> Result<XPanUploadResponse, YunPanError> {
-
-        let mut url = Url::parse("https://c.pcs.baidu.com/rest/2.0/pcs/superfile2").unwrap();
-        url.query_pairs_mut()
-            .append_pair("method", "upload")
-            .append_pair("access_token", &self.access_token)
-            .append_pair("type", "tmpfile")
-            .append_pair("path", path)
-            .append_pair("uploadid", upload_id)
-            .append_pair("partseq", &slice_file.seq.to_string());
- 
-        let buffer = slice_file.read().await?;
+}
 
-        let file_part  = reqwest::multipart::Part::bytes(buffer).file_name("filename");//必须指定file_name
-        let form = reqwest::multipart::Form::new().part("file", file_part);
+//create接口的响应, 失败时只有errno
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanCreateResponse {
+    errno: Option<i32>, //错误码 0：表示成功 -8:文件或目录已存在
+    #[serde(flatten)]
+    file: RemoteFile,
+}
 
-        let response = self.client.post(url).multipart(form).send().await?;
+/**
+ * stat的结果
+ */
+#[derive(Debug, Serialize)]
+pub struct CliFileStat(RemoteFile);
+impl std::fmt::Display for CliFileStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = &self.0;
+        writeln!(f, "path:     {}", file.path)?;
+        writeln!(f, "type:     {}", if file.isdir == 1 { "directory" } else { "file" })?;
+        writeln!(f, "fs_id:    {}", file.fs_id)?;
+        if file.isdir != 1 {
+            writeln!(f, "size:     {} ({})", file.size, human_size(file.size))?;
+            writeln!(f, "md5:      {}", file.md5.as_deref().unwrap_or("-"))?;
+            writeln!(f, "category: {}", file.category_name())?;
+        }
+        writeln!(f, "ctime:    {}", format_timestamp(file.server_ctime))?;
+        write!(f, "mtime:    {}", format_timestamp(file.server_mtime))?;
+        if let Some(dlink) = &file.dlink {
+            write!(f, "\ndlink:    {}", dlink)?;
+        }
+        for (name, url) in file.thumbs.iter().flatten() {
+            write!(f, "\n{:<10}{}", format!("{}:", name), url)?;
+        }
+        for (name, value) in &file.extra {
+            write!(f, "\n{:<10}{}", format!("{}:", name), value)?;
+        }
+        Ok(())
+    }
+}
 
-        //let response_body: XPanUploadResponse = response.json().await?;
-        let raw_response_text = response.text().await?; 
-
-        match serde_json::from_str::<XPanUploadResponse>(&raw_response_text) {
-            Ok(response) => {
-                return if let Some(0) = response.error_code.or_else(|| Some(0)) {//没有的话! 默认为0,有md5,request_id就行
-                    Ok(response)
-                } else{
-                    Err(YunPanError::Biz(format!("upload slice failed on seq:{}, {:?}",slice_file.seq,raw_response_text)))
-                }
-            },
-            Err(e) => {
-                eprintln!("serde_json::from_str failed on upload_slice response: {:?}",raw_response_text);
-                return Err(YunPanError::Serde(e))//解析错误
-            }
-        };
+/**
+ * 单个文件上传的结果, slice_retries为重试过的分片(序号 -> 重试次数)
+ */
+#[derive(Debug, Serialize)]
+pub struct CliUploadResponse {
+    #[serde(flatten)]
+    file: RemoteFile,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    slice_retries: BTreeMap<u64, u32>,
+}
+impl From<RemoteFile> for CliUploadResponse {
+    fn from(file: RemoteFile) -> Self {
+        CliUploadResponse { file, slice_retries: BTreeMap::new() }
     }
-    
-    
+}
+impl std::fmt::Display for CliUploadResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.file)?;
+        if !self.slice_retries.is_empty() {
+            let retries: Vec<String> = self.slice_retries.iter()
+                .map(|(seq, retries)| format!("{}x{}", seq, retries))
+                .collect();
+            write!(f, " retried slices(seq x times):{}", retries.join(","))?;
+        }
+        Ok(())
+    }
+}
 
-    //doc: https://pan.baidu.com/union/doc/rksg0sa17
-    async fn create(
-        &self,
-        request: &XPanFileCreateRequest, 
-    ) -> Result<XPanCreateResponse, YunPanError> {
-        let url = format!(
-            "https://pan.baidu.com/rest/2.0/xpan/file?method=create&access_token={}",
-            self.access_token
-        );
-        println!("create::  upload_id:{} , request:{:?}", request.uploadid, request);
-        let request_builder = self.client.post(&url).form(request);
+#[derive(Debug, Serialize)]
+pub struct CliUploadFailure {
+    file_path: String,
+    error: String,
+}
 
-        let response = request_builder.send().await?;
-        let raw_response_text = response.text().await?; 
-
-        match serde_json::from_str::<XPanCreateResponse>(&raw_response_text) {
-            Ok(response) => {
-                return if let Some(0) = response.errno {//errno=0表示上传成功,没有的情况或者不是0的情况都是有问题的
-                    Ok(response)
-                } else{
-                    Err(YunPanError::Biz(format!("create failed: {:?}", raw_response_text)))
-                }
-            },
-            Err(e) => {
-                println!("serde_json::from_str failed on create response: {:?}",raw_response_text);
-                return Err(YunPanError::Serde(e))//解析错误
-            }
-        };
+/**
+ * 目录上传的结果汇总
+ */
+#[derive(Debug, Serialize, Default)]
+pub struct CliUploadSummary {
+    succeeded: Vec<CliUploadResponse>,
+    failed: Vec<CliUploadFailure>,
+}
+impl CliUploadSummary {
+    pub fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+}
+impl std::fmt::Display for CliUploadSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for response in &self.succeeded {
+            writeln!(f, "OK     {}", response)?;
+        }
+        for failure in &self.failed {
+            writeln!(f, "FAILED {} {}", failure.file_path, failure.error)?;
+        }
+        write!(f, "{} succeeded, {} failed", self.succeeded.len(), self.failed.len())
     }
+}
 
-    pub async fn upload(&self, request: CliUploadRequest) -> Result<XPanCreateResponse, YunPanError> {
-        let upload_file = UploadFile::new(&request.file_path).await?;
-        let file_size = upload_file.file_size;
-        //split(物理切割) vs split2(逻辑分割)
-        let mut slice_files = upload_file.split2(request.chunk_size).await?;
-        //排序 保证下面的block_list得到的顺序是按照seq来的,但是发送(upload_slice)的顺序随意 保证 block_list的位置即可
-        slice_files.sort_by(|a, b| a.seq.cmp(&b.seq));
-   
-        let block_list: Vec<String> = slice_files.iter().map(|sf|  sf.md5.clone()).collect();
+//文件管理接口的响应 errno=12时为批量操作部分失败, 每个文件的结果在info中; 异步执行时返回taskid
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanFileManagerResponse {
+    errno: Option<i32>,
+    #[serde(default)]
+    info: Vec<XPanFileManagerInfo>,
+    #[serde(default)]
+    taskid: u64,
+}
 
-        let upload_file_path: String = Path::new("/apps/asitanokibou/")
-                                            .join(upload_file.file_name.clone())
-                                            .to_str().unwrap().to_string();
-        //1. 预上传
-        let pcreate_request  = 
-            XPanFilePreCreateRequest::new(&upload_file_path, file_size, &block_list);
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanFileManagerInfo {
+    #[serde(default)]
+    errno: i32,
+    #[serde(default)]
+    path: String,
+}
 
-        let response =  self.precreate(&pcreate_request).await?;
-        let upload_id = response.upload_id.as_str();
-        println!("precreate::  upload_id:{}", upload_id );
+/** 等待异步任务完成的最长时间 */
+const TASK_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/** 查询异步任务时连续返回未知状态的最大次数 */
+const TASK_MAX_UNKNOWN_POLLS: u32 = 5;
 
-        let slice_file_paths : hash_set::HashSet<String> = 
-            slice_files.iter()
-                .map(|sf| sf.file_path.to_string().clone())
-                .collect();
+//异步任务的状态
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanTaskQueryResponse {
+    errno: Option<i32>,
+    #[serde(default)]
+    status: String, //pending/running/success/failed
+    #[serde(default)]
+    task_errno: i32,
+}
 
+#[derive(Debug, Serialize)]
+pub struct CliFileManagerFailure {
+    path: String,
+    errno: i32,
+}
 
-        //2. 分片上传    //暂时先单线程上传 
-        for slice_file in slice_files {
-            println!("uploading slice:{} md5:{}", slice_file.seq,slice_file.md5.as_str());
-            //upload_slice vs upload_slice2
-            let upload_slice_response = self.upload_slice2(&upload_file_path, upload_id, &slice_file).await?;
-            assert!(upload_slice_response.md5.eq(slice_file.md5.as_str()), "md5 not match");
-            //注意 物理分割时 file_path为分片的路径, 逻辑分割时file_path为源文件路径!
+/**
+ * 复制/移动/重命名/删除的结果
+ */
+#[derive(Debug, Serialize)]
+pub struct CliFileManagerResponse {
+    operation: &'static str,
+    taskid: Option<u64>, //异步执行时的任务id
+    succeeded: Vec<String>,
+    failed: Vec<CliFileManagerFailure>,
+}
+impl CliFileManagerResponse {
+    pub fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+}
+impl std::fmt::Display for CliFileManagerResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for path in &self.succeeded {
+            writeln!(f, "OK     {} {}", self.operation, path)?;
+        }
+        for failure in &self.failed {
+            writeln!(f, "FAILED {} {} errno:{}", self.operation, failure.path, failure.errno)?;
         }
+        write!(f, "{} succeeded, {} failed", self.succeeded.len(), self.failed.len())
+    }
+}
 
-        // - 删除临时文件
-        if slice_file_paths.len() > 1 {// =1的时候证明没有切片,即为源文件/ 或者是逻辑切分的所以这里的slice_file也是源文件的路径  
-            println!("removing slice files: {:?}", slice_file_paths);
-            for slice_path in slice_file_paths.iter() {
-                tokio::fs::remove_file(&slice_path).await?;
-            }
+//网盘容量 doc: https://pan.baidu.com/union/doc/Cksg0s9ic
+#[derive(Debug, Serialize, Deserialize)]
+pub struct XPanQuotaResponse {
+    errno: Option<i32>,
+    total: u64, //总空间大小 单位B
+    used: u64, //已使用大小 单位B
+    #[serde(default)]
+    free: u64, //剩余大小 单位B (checkfree=1时返回)
+    #[serde(default)]
+    expire: bool, //7天内是否有容量到期 (checkexpire=1时返回)
+}
+impl XPanQuotaResponse {
+    /** 可用空间, 没有返回free时用 total - used */
+    pub fn available(&self) -> u64 {
+        if self.free > 0 { self.free } else { self.total.saturating_sub(self.used) }
+    }
+}
+impl std::fmt::Display for XPanQuotaResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "total:{} used:{} free:{}", human_size(self.total), human_size(self.used), human_size(self.available()))?;
+        if self.expire {
+            write!(f, " (some space expires within 7 days)")?;
         }
+        Ok(())
+    }
+}
 
-        //3. 创建文件
-        let create_request = XPanFileCreateRequest::new(
-            &upload_file_path,
+//用户信息 doc: https://pan.baidu.com/union/doc/pksg0s9ns
+#[derive(Debug, Serialize, Deserialize)]
+pub struct XPanUserInfoResponse {
+    errno: Option<i32>,
+    baidu_name: String, //百度账号
+    #[serde(default)]
+    netdisk_name: String, //网盘账号
+    #[serde(default)]
+    avatar_url: String, //头像地址
+    #[serde(default)]
+    vip_type: u8, //会员类型 0普通用户 1普通会员 2超级会员
+    uk: u64, //用户id
+}
+impl std::fmt::Display for XPanUserInfoResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let vip = match self.vip_type {
+            1 => "vip",
+            2 => "svip",
+            _ => "normal",
+        };
+        write!(f, "{} uk:{} vip_type:{}", self.baidu_name, self.uk, vip)
+    }
+}
+
+/**
+ * mkdir的结果 created为这次新建的目录(父目录在前)
+ */
+#[derive(Debug, Serialize)]
+pub struct CliMkdirResponse {
+    path: String,
+    created: Vec<RemoteFile>,
+}
+impl std::fmt::Display for CliMkdirResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.created.is_empty() {
+            return write!(f, "{} already exists", self.path);
+        }
+        for (i, dir) in self.created.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "created {} fs_id:{}", dir.path, dir.fs_id)?;
+        }
+        Ok(())
+    }
+}
+
+//秒传: 云端已有相同内容的文件时直接创建, 不需要上传数据
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanRapidUploadRequest {
+    path: String,
+    #[serde(rename = "content-length")]
+    content_length: u64,
+    #[serde(rename = "content-md5")]
+    content_md5: String, //整个文件的md5
+    #[serde(rename = "slice-md5")]
+    slice_md5: String, //文件前256KB的md5
+    rtype: u32,
+}
+
+/** 秒传校验段的大小, 文件小于这个大小不能秒传 */
+const RAPID_UPLOAD_SLICE_SIZE: u64 = 256 * 1024;
+
+impl XPanRapidUploadRequest {
+    fn new(path: &str, size: u64, content_md5: String, slice_md5: String, rtype: u32) -> Self {
+        XPanRapidUploadRequest {
+            path: path.to_string(),
+            content_length: size,
+            content_md5,
+            slice_md5,
+            rtype,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanRapidUploadResponse {
+    errno: Option<i32>, //0 成功, 404/31079 云端没有相同内容的文件
+    info: Option<RemoteFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanFileListResponse {
+    errno: Option<i32>,
+    #[serde(default)]
+    list: Vec<RemoteFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanListAllResponse {
+    errno: Option<i32>,
+    #[serde(default)]
+    has_more: u8, //1 还有下一页
+    #[serde(default)]
+    cursor: u32, //下一页的start
+    #[serde(default)]
+    list: Vec<RemoteFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanSearchResponse {
+    errno: Option<i32>,
+    #[serde(default)]
+    has_more: u8, //1 还有下一页
+    #[serde(default)]
+    list: Vec<RemoteFile>,
+}
+
+/**
+ * ls/find的结果, human为true时大小显示为 1.5M 这样的格式
+ */
+#[derive(Debug, Serialize)]
+pub struct CliFileList {
+    files: Vec<RemoteFile>,
+    #[serde(skip)]
+    human: bool,
+}
+impl CliFileList {
+    pub fn new(files: Vec<RemoteFile>, human: bool) -> Self {
+        CliFileList { files, human }
+    }
+}
+impl std::fmt::Display for CliFileList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sizes: Vec<String> = self.files.iter().map(|file| match (file.isdir, self.human) {
+            (1, _) => "-".to_string(),
+            (_, true) => human_size(file.size),
+            (_, false) => file.size.to_string(),
+        }).collect();
+        let width = sizes.iter().map(|s| s.len()).max().unwrap_or(0);
+        for (i, (file, size)) in self.files.iter().zip(sizes).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{} {:>width$} {} {}{}",
+                if file.isdir == 1 { "d" } else { "-" },
+                size,
+                format_timestamp(file.server_mtime),
+                file.path,
+                if file.isdir == 1 { "/" } else { "" })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct XPanUploadResponse { 
+    md5: String, //文件切片云端md5
+    error_code: Option<i64>, //错误码  None为成功!(tmd api都不同格式的) 失败时由read_response处理
+    error_msg: Option<String>,
+}
+
+impl YunPanService {
+    pub fn new(tokens: TokenProvider, options: ServiceOptions) -> Result<Self, YunPanError> {
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(30));
+        if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        let client = builder.build()?;
+
+        let app_root = options.app_root.as_deref().unwrap_or(DEFAULT_APP_ROOT);
+        //不合法时直接报错, 不能悄悄换成默认目录 (文件会被上传到用户没有指定的地方)
+        let app_root = normalize_remote_path(app_root).ok_or_else(|| YunPanError::InvalidPath(
+            format!("invalid app root {}, it must be an absolute path without ..", app_root)))?;
+
+        Ok(YunPanService {
+            tokens,
+            app_root,
+            client,
+            upload_limiter: options.upload_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+            download_limiter: options.download_limit.map(RateLimiter::new),
+            retry: RetryPolicy {
+                max_attempts: options.max_attempts.unwrap_or(RetryPolicy::default().max_attempts).max(1),
+                ..RetryPolicy::default()
+            },
+            progress_mode: options.progress,
+        })
+    }
+
+    /**
+     * 调用接口, 成功时同时返回重试的次数. 失败时:
+     * 1. token过期时刷新token并重试一次(固定的token没法刷新,直接返回错误)
+     * 2. 临时性的错误(超时/连接失败/5xx/频控等)按指数退避重试, 最多尝试retry.max_attempts次
+     *    idempotent为false时只在确定服务端没有处理请求时重试 (见is_retryable_unprocessed)
+     */
+    async fn with_retry_counted<T, F, Fut>(&self, idempotent: bool, call: F) -> Result<(T, u32), YunPanError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, YunPanError>>,
+    {
+        let mut refreshed = false;
+        let mut attempt = 1;
+        loop {
+            //token过期需要刷新时, 刷新请求的临时性错误也一样重试
+            let token = match self.tokens.access_token().await {
+                Ok(token) => token,
+                Err(e) if e.is_retryable() && attempt < self.retry.max_attempts => {
+                    let delay = self.retry.delay(attempt);
+                    log::warn!("{}, retrying in {:?} ({}/{})", e, delay, attempt, self.retry.max_attempts - 1);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            match call().await {
+                Ok(value) => return Ok((value, attempt - 1)),
+                Err(YunPanError::AuthExpired(msg)) if !refreshed => {
+                    if !self.tokens.refresh(&token).await? {
+                        return Err(YunPanError::AuthExpired(msg));
+                    }
+                    refreshed = true;
+                },
+                Err(e) if attempt < self.retry.max_attempts
+                    && (if idempotent { e.is_retryable() } else { e.is_retryable_unprocessed() }) => {
+                    let delay = self.retry.delay(attempt);
+                    log::warn!("{}, retrying in {:?} ({}/{})", e, delay, attempt, self.retry.max_attempts - 1);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn with_retry<T, F, Fut>(&self, call: F) -> Result<T, YunPanError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, YunPanError>>,
+    {
+        self.with_retry_counted(true, call).await.map(|(value, _)| value)
+    }
+
+    /**
+     * 非幂等的请求(重复执行会产生不同的结果), 超时等不确定是否已经处理的错误不重试
+     */
+    async fn with_retry_non_idempotent<T, F, Fut>(&self, call: F) -> Result<T, YunPanError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, YunPanError>>,
+    {
+        self.with_retry_counted(false, call).await.map(|(value, _)| value)
+    }
+
+    /**
+     * 远程路径: 相对路径基于app_root, 并去掉多余的 . 和 /
+     */
+    fn remote_path(&self, path: &str) -> Result<String, YunPanError> {
+        let absolute = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.app_root, path)
+        };
+        normalize_remote_path(&absolute)
+            .ok_or_else(|| YunPanError::InvalidPath(format!("invalid remote path: {}", path)))
+    }
+
+    /**
+     * 上传的目标路径, 必须在app_root下(否则接口会返回-7 没有权限)
+     */
+    fn upload_path(&self, path: &str) -> Result<String, YunPanError> {
+        let path = self.remote_path(path)?;
+        if !path.starts_with(&format!("{}/", self.app_root)) {
+            return Err(YunPanError::InvalidPath(format!("remote path {} is outside of app root {}", path, self.app_root)));
+        }
+        Ok(path)
+    }
+
+    /** 接口地址加上method和当前的access_token, 其他参数由调用方追加 */
+    async fn api_url(&self, base: &str, method: Option<&str>) -> Result<Url, YunPanError> {
+        let access_token = self.tokens.access_token().await?;
+        let mut url = Url::parse(base).unwrap();//都是常量
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(method) = method {
+                query.append_pair("method", method);
+            }
+            query.append_pair("access_token", &access_token);
+        }
+        Ok(url)
+    }
+
+    //预上传 doc : https://pan.baidu.com/union/doc/3ksg0s9r7
+    async fn precreate(
+        &self,
+        request: &XPanFilePreCreateRequest,
+    ) -> Result<XPanPrecreateResponse, YunPanError> {
+        let url = self.api_url(XPAN_FILE_URL, Some("precreate")).await?;
+
+        let response: reqwest::Response = self.client.post(url).form(request).send().await?;
+    
+        let raw_response_text = read_response(response, &format!("precreate {}", request.path), &[]).await?; // 直接转json response.json::<XPanPrecreateResponse>()
+
+        //let text: String = response.text().await.expect("failed to get response text");
+        parse_response::<XPanPrecreateResponse>(&raw_response_text, "precreate")
+    }
+
+
+    //doc : https://pan.baidu.com/union/doc/nksg0s9vi
+    #[allow(dead_code)]
+    async fn upload_slice(
+        &self,
+        path: &str,
+        upload_id: &str,
+        slice_file: &SliceFile,
+    ) -> Result<XPanUploadResponse, YunPanError> {
+     
+        let mut url = self.api_url(PCS_SUPERFILE2_URL, Some("upload")).await?;
+        url.query_pairs_mut()
+            .append_pair("type", "tmpfile")
+            .append_pair("path", path)
+            .append_pair("uploadid", upload_id)
+            .append_pair("partseq", &slice_file.seq.to_string());
+ 
+        let size = tokio::fs::metadata(&slice_file.file_path).await?.len();
+        let body = reqwest::Body::wrap_stream(
+            file_range_stream(PathBuf::from(&slice_file.file_path), 0, size, UPLOAD_CHUNK_SIZE));
+
+        let file_part  = reqwest::multipart::Part::stream_with_length(body, size).file_name("filename");//必须指定file_name
+        let form = reqwest::multipart::Form::new().part("file", file_part);
+
+        let response = self.client.post(url).multipart(form).send().await?;
+
+        //let response_body: XPanUploadResponse = response.json().await?;
+        //成功时没有error_code, 只有md5和request_id
+        let raw_response_text = read_response(response, &format!("upload slice seq:{}", slice_file.seq), &[]).await?;
+
+        parse_response::<XPanUploadResponse>(&raw_response_text, "upload_slice")
+    }
+    async fn upload_slice2(
+        &self,
+        path: &str,
+        upload_id: &str,
+        slice_file: &SliceFileInfo<'_>,
+        progress: &Progress,
+    ) -> Result<XPanUploadResponse, YunPanError> {
+
+        let mut url = self.api_url(PCS_SUPERFILE2_URL, Some("upload")).await?;
+        url.query_pairs_mut()
+            .append_pair("type", "tmpfile")
+            .append_pair("path", path)
+            .append_pair("uploadid", upload_id)
+            .append_pair("partseq", &slice_file.seq.to_string());
+ 
+        let sent = Arc::new(AtomicU64::new(0));
+        let body = counting_body(slice_file.stream(UPLOAD_CHUNK_SIZE), self.upload_limiter.clone(), progress.clone(), sent.clone());
+
+        let file_part  = reqwest::multipart::Part::stream_with_length(body, slice_file.size).file_name("filename");//必须指定file_name
+        let form = reqwest::multipart::Form::new().part("file", file_part);
+
+        //限速时发送一个分片可能需要很久, 不能用默认的30s
+        let timeout = Duration::from_secs(600)
+            + self.upload_limiter.as_ref().map(|limiter| limiter.duration_for(slice_file.size)).unwrap_or_default();
+        let result = async {
+            let response = self.client.post(url).multipart(form).timeout(timeout).send().await?;
+
+            //let response_body: XPanUploadResponse = response.json().await?;
+            //成功时没有error_code, 只有md5和request_id
+            let raw_response_text = read_response(response, &format!("upload slice seq:{}", slice_file.seq), &[]).await?;
+
+            parse_response::<XPanUploadResponse>(&raw_response_text, "upload_slice")
+        }.await;
+        if result.is_err() {
+            //重试时会重新计入
+            progress.rollback(sent.load(Ordering::Relaxed));
+        }
+        result
+    }
+    
+    
+
+    //doc: https://pan.baidu.com/union/doc/rksg0sa17
+    async fn create(
+        &self,
+        request: &XPanFileCreateRequest, 
+    ) -> Result<RemoteFile, YunPanError> {
+        let url = self.api_url(XPAN_FILE_URL, Some("create")).await?;
+        log::debug!("create::  upload_id:{} , request:{:?}", request.uploadid, request);
+        let request_builder = self.client.post(url).form(request);
+
+        let response = request_builder.send().await?;
+        let raw_response_text = read_response(response, &format!("create {}", request.path), &[]).await?;
+
+        let response = parse_response::<XPanCreateResponse>(&raw_response_text, "create")?;
+        Ok(response.file)
+    }
+
+    /**
+     * 创建目录 目录已存在时返回None
+     * rtype固定为0, 否则已存在时会创建一个重命名的目录
+     */
+    async fn create_dir(&self, path: &str) -> Result<Option<RemoteFile>, YunPanError> {
+        let url = self.api_url(XPAN_FILE_URL, Some("create")).await?;
+        let request = XPanFileCreateRequest::new_dir(path, ConflictPolicy::Fail.rtype());
+        let response = self.client.post(url).form(&request).send().await?;
+        let raw_response_text = read_response(response, &format!("create dir {}", path), &[-8]).await?;
+        if let Ok(XPanErrnoResponse { errno: Some(-8), .. }) = serde_json::from_str(&raw_response_text) {
+            return Ok(None);//已存在
+        }
+
+        let response = parse_response::<XPanCreateResponse>(&raw_response_text, "create")?;
+        Ok(Some(response.file))
+    }
+
+    //秒传 成功时返回创建的文件, 云端没有相同内容的文件时返回None
+    async fn rapid_upload(
+        &self,
+        request: &XPanRapidUploadRequest,
+    ) -> Result<Option<RemoteFile>, YunPanError> {
+        let url = self.api_url(XPAN_FILE_URL, Some("rapidupload")).await?;
+
+        let response = self.client.post(url).form(request).send().await?;
+        //404/31079 云端没有相同内容的文件
+        let raw_response_text = read_response(response, &format!("rapidupload {}", request.path), &[404, 31079]).await?;
+
+        match parse_response::<XPanRapidUploadResponse>(&raw_response_text, "rapidupload")? {
+            XPanRapidUploadResponse { errno: Some(0), info: Some(info) } => Ok(Some(info)),
+            _ => Ok(None),
+        }
+    }
+
+    //列出目录下的一页文件 doc: https://pan.baidu.com/union/doc/nksg0sat9
+    async fn list_page(&self, dir: &str, start: u32, limit: u32, options: &ListOptions) -> Result<Vec<RemoteFile>, YunPanError> {
+        let mut url = self.api_url(XPAN_FILE_URL, Some("list")).await?;
+        url.query_pairs_mut()
+            .append_pair("dir", dir)
+            .append_pair("order", options.order.as_param())
+            .append_pair("start", &start.to_string())
+            .append_pair("limit", &limit.to_string());
+        if options.desc {
+            url.query_pairs_mut().append_pair("desc", "1");
+        }
+        if options.dirs_only {
+            url.query_pairs_mut().append_pair("folder", "1");
+        }
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = read_response(response, &format!("list {}", dir), &[]).await?;
+
+        let response = parse_response::<XPanFileListResponse>(&raw_response_text, "list")?;
+        Ok(response.list)
+    }
+
+    //递归获取文件列表 doc: https://pan.baidu.com/union/doc/Zlvaaot4t 返回(本页文件, 下一页的start)
+    async fn listall_page(&self, path: &str, start: u32, limit: u32, options: &ListOptions) -> Result<(Vec<RemoteFile>, Option<u32>), YunPanError> {
+        let mut url = self.api_url(XPAN_MULTIMEDIA_URL, Some("listall")).await?;
+        url.query_pairs_mut()
+            .append_pair("path", path)
+            .append_pair("recursion", "1")
+            .append_pair("order", options.order.as_param())
+            .append_pair("desc", if options.desc { "1" } else { "0" })
+            .append_pair("start", &start.to_string())
+            .append_pair("limit", &limit.to_string());
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = read_response(response, &format!("listall {}", path), &[]).await?;
+
+        let response = parse_response::<XPanListAllResponse>(&raw_response_text, "listall")?;
+        let next = if response.has_more == 1 { Some(response.cursor) } else { None };
+        Ok((response.list, next))
+    }
+
+    /**
+     * 列出远程目录下的文件, 分页由这里处理 (不指定目录时为应用目录)
+     */
+    pub async fn list(&self, dir: Option<&str>, options: &ListOptions) -> Result<Vec<RemoteFile>, YunPanError> {
+        let dir = match dir {
+            Some(dir) => self.remote_path(dir)?,
+            None => self.app_root.clone(),
+        };
+        const PAGE_SIZE: u32 = 1000;
+        let mut files = Vec::new();
+        let mut start = 0;
+        if options.recursive {
+            loop {
+                let (page, next) = self.with_retry(|| self.listall_page(&dir, start, PAGE_SIZE, options)).await?;
+                files.extend(page);
+                match next {
+                    Some(next) => start = next,
+                    None => break,
+                }
+            }
+            //listall没有只返回目录的参数
+            if options.dirs_only {
+                files.retain(|f| f.isdir == 1);
+            }
+        } else {
+            loop {
+                let page = self.with_retry(|| self.list_page(&dir, start, PAGE_SIZE, options)).await?;
+                let page_len = page.len() as u32;
+                files.extend(page);
+                if page_len < PAGE_SIZE {
+                    break;
+                }
+                start += PAGE_SIZE;
+            }
+        }
+        log::debug!("listed {} entries under {}", files.len(), dir);
+        Ok(files)
+    }
+
+    //搜索文件 doc: https://pan.baidu.com/union/doc/zksg0sb9z page从1开始, num最大1000
+    async fn search_once(
+        &self,
+        key: &str,
+        dir: &str,
+        recursion: bool,
+        category: Option<FileCategory>,
+        page: u32,
+        num: u32,
+    ) -> Result<(Vec<RemoteFile>, bool), YunPanError> {
+        let mut url = self.api_url(XPAN_FILE_URL, Some("search")).await?;
+        url.query_pairs_mut()
+            .append_pair("key", key)
+            .append_pair("dir", dir)
+            .append_pair("recursion", if recursion { "1" } else { "0" })
+            .append_pair("page", &page.to_string())
+            .append_pair("num", &num.to_string());
+        if let Some(category) = category {
+            url.query_pairs_mut().append_pair("category", &category.code().to_string());
+        }
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = read_response(response, &format!("search {}", key), &[]).await?;
+
+        let response = parse_response::<XPanSearchResponse>(&raw_response_text, "search")?;
+        Ok((response.list, response.has_more == 1))
+    }
+
+    /**
+     * 按关键字搜索文件名的一页, 返回(本页的文件, 是否还有下一页) 不指定目录时为应用目录
+     */
+    pub async fn search_page(
+        &self,
+        key: &str,
+        dir: Option<&str>,
+        recursion: bool,
+        category: Option<FileCategory>,
+        page: u32,
+        num: u32,
+    ) -> Result<(Vec<RemoteFile>, bool), YunPanError> {
+        let dir = match dir {
+            Some(dir) => self.remote_path(dir)?,
+            None => self.app_root.clone(),
+        };
+        self.with_retry(|| self.search_once(key, &dir, recursion, category, page.max(1), num.clamp(1, 1000))).await
+    }
+
+    /**
+     * 按关键字搜索文件名, 返回所有页的结果
+     */
+    pub async fn search(&self, key: &str, dir: Option<&str>, recursion: bool, category: Option<FileCategory>) -> Result<Vec<RemoteFile>, YunPanError> {
+        const PAGE_SIZE: u32 = 1000;
+        let mut files = Vec::new();
+        let mut page = 1;
+        loop {
+            let (found, has_more) = self.search_page(key, dir, recursion, category, page, PAGE_SIZE).await?;
+            files.extend(found);
+            if !has_more {
+                break;
+            }
+            page += 1;
+        }
+        log::debug!("found {} entries matching {}", files.len(), key);
+        Ok(files)
+    }
+
+    //查询文件信息 doc: https://pan.baidu.com/union/doc/Fksg0sbcm 一次最多100个fs_id
+    async fn filemetas(&self, fs_ids: &[u64], dlink: bool, thumb: bool, extra: bool) -> Result<Vec<RemoteFile>, YunPanError> {
+        let mut url = self.api_url(XPAN_MULTIMEDIA_URL, Some("filemetas")).await?;
+        url.query_pairs_mut()
+            .append_pair("fsids", &serde_json::to_string(fs_ids).unwrap())
+            .append_pair("dlink", if dlink { "1" } else { "0" })
+            .append_pair("thumb", if thumb { "1" } else { "0" })
+            .append_pair("extra", if extra { "1" } else { "0" });
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = read_response(response, "filemetas", &[]).await?;
+
+        let response = parse_response::<XPanFileListResponse>(&raw_response_text, "filemetas")?;
+        Ok(response.list)
+    }
+
+    /**
+     * 查询文件信息, 超过100个时分批查询
+     * dlink: 返回下载地址 thumb: 返回缩略图地址 extra: 返回图片/视频的额外信息
+     */
+    pub async fn file_metas(&self, fs_ids: &[u64], dlink: bool, thumb: bool, extra: bool) -> Result<Vec<RemoteFile>, YunPanError> {
+        const BATCH_SIZE: usize = 100;
+        let mut files = Vec::with_capacity(fs_ids.len());
+        for batch in fs_ids.chunks(BATCH_SIZE) {
+            files.extend(self.with_retry(|| self.filemetas(batch, dlink, thumb, extra)).await?);
+        }
+        Ok(files)
+    }
+
+    /**
+     * 根据远程路径查询文件信息 (文件会同时返回dlink)
+     */
+    pub async fn stat(&self, remote_path: &str, thumb: bool, extra: bool) -> Result<CliFileStat, YunPanError> {
+        let remote_path = self.remote_path(remote_path)?;
+        let file = self.find_file(&remote_path).await?;
+        let file = self.file_metas(&[file.fs_id], file.isdir != 1, thumb, extra).await?
+            .into_iter().next()
+            .ok_or_else(|| YunPanError::Biz(format!("filemetas returned nothing for {}", remote_path)))?;
+        Ok(CliFileStat(file))
+    }
+
+    /**
+     * 根据远程路径找到对应的文件(接口只能按fs_id查询, 所以先列出父目录再按路径匹配)
+     */
+    async fn find_file(&self, remote_path: &str) -> Result<RemoteFile, YunPanError> {
+        let remote_path = remote_path.trim_end_matches('/');
+        let dir = Path::new(remote_path).parent()
+            .and_then(|p| p.to_str())
+            .ok_or_else(|| YunPanError::Biz(format!("invalid remote path: {}", remote_path)))?;
+
+        const PAGE_SIZE: u32 = 1000;
+        let options = ListOptions::default();
+        let mut start = 0;
+        loop {
+            let page = self.with_retry(|| self.list_page(dir, start, PAGE_SIZE, &options)).await?;
+            let page_len = page.len() as u32;
+            if let Some(file) = page.into_iter().find(|f| f.path == remote_path) {
+                return Ok(file);
+            }
+            if page_len < PAGE_SIZE {
+                return Err(YunPanError::NotFound(format!("remote file not found: {}", remote_path)));
+            }
+            start += PAGE_SIZE;
+        }
+    }
+
+    /**
+     * 下载一个分段, 写入到本地文件的对应位置
+     */
+    async fn download_range(&self, dlink: &str, local_path: &Path, range: &RangeInfo, progress: &Progress) -> Result<u64, YunPanError> {
+        let access_token = self.tokens.access_token().await?;
+        let mut url = Url::parse(dlink).map_err(|e| YunPanError::Biz(format!("invalid dlink {}: {}", dlink, e)))?;
+        url.query_pairs_mut().append_pair("access_token", &access_token);
+
+        let response = self.client.get(url)
+            .header(reqwest::header::USER_AGENT, "pan.baidu.com")//dlink要求UA必须为pan.baidu.com
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", range.start(), range.end()))
+            .timeout(Duration::from_secs(600))//一个分段可能比较大,不能用默认的30s
+            .send().await?;
+
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(YunPanError::from_status(response.status(), format!("download range seq:{} failed", range.seq)));
+        }
+
+        let mut written = 0u64;
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new().write(true).open(local_path).await?;
+            file.seek(std::io::SeekFrom::Start(range.start())).await?;
+
+            let mut stream = response.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                if let Some(limiter) = &self.download_limiter {
+                    limiter.acquire(chunk.len() as u64).await;
+                }
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                progress.inc(chunk.len() as u64);
+            }
+            file.flush().await?;
+            file.sync_data().await?;//落盘后才能记录为已完成
+            Ok::<(), YunPanError>(())
+        }.await;
+        if let Err(e) = result {
+            //重试时整个分段会重新下载
+            progress.rollback(written);
+            return Err(e);
+        }
+
+        if written != range.size {
+            return Err(YunPanError::Biz(format!("download range seq:{} incomplete, got:{} expect:{}", range.seq, written, range.size)));
+        }
+        Ok(written)
+    }
+
+    pub async fn download(&self, request: CliDownloadRequest) -> Result<CliDownloadResponse, YunPanError> {
+        //1. 路径 -> fs_id -> dlink
+        let remote_path = self.remote_path(&request.remote_path)?;
+        let file = self.find_file(&remote_path).await?;
+        if file.isdir == 1 {
+            return Err(YunPanError::Biz(format!("{} is a directory", remote_path)));
+        }
+        let file = self.file_metas(&[file.fs_id], true, false, false).await?
+            .into_iter().next()
+            .ok_or_else(|| YunPanError::Biz(format!("filemetas returned nothing for {}", remote_path)))?;
+        let dlink = file.dlink.clone()
+            .ok_or_else(|| YunPanError::Biz(format!("no dlink for {}", remote_path)))?;
+
+        let file_name = Path::new(&file.path).file_name().unwrap().to_str().unwrap().to_string();
+        let local_path = match &request.local_path {
+            Some(p) if Path::new(p).is_dir() => Path::new(p).join(&file_name),
+            Some(p) => PathBuf::from(p),
+            None => PathBuf::from(&file_name),
+        };
+
+        //2. 先写到.part文件, 旁边的.part.json记录已完成的分段, 中断后再次下载时只下载缺少的分段
+        let part_path = DownloadJournal::part_path(&local_path);
+        let mut journal = DownloadJournal::load(&local_path, file.fs_id, file.size, file.md5.clone(), request.chunk_size).await;
+        if journal.completed.is_empty() {
+            //预分配本地文件, 各个分段直接写入对应位置
+            let part_file = tokio::fs::File::create(&part_path).await?;
+            part_file.set_len(file.size).await?;
+        } else {
+            log::info!("resuming download, {} ranges already downloaded", journal.completed.len());
+        }
+        journal.save().await?;
+
+        //3. 多连接分段下载
+        let ranges = split_range(file.size, request.chunk_size);
+        log::info!("downloading {} ({} bytes, {} ranges) to {:?}", file.path, file.size, ranges.len(), local_path);
+        {
+            let (pending_ranges, done_ranges): (Vec<&RangeInfo>, Vec<&RangeInfo>) = ranges.iter()
+                .partition(|range| !journal.completed.contains(&range.seq));
+            let done_size = done_ranges.iter().map(|range| range.size).sum();
+            let progress = Progress::new(self.progress_mode, &file_name, file.size, done_size);
+            let progress_ref = &progress;
+            let part_path_ref = part_path.as_path();
+            let dlink_ref = dlink.as_str();
+            let mut downloads = futures::stream::iter(pending_ranges)
+                .map(|range| async move {
+                    log::debug!("downloading range:{} bytes={}-{}", range.seq, range.start(), range.end());
+                    self.with_retry(|| self.download_range(dlink_ref, part_path_ref, range, progress_ref)).await.map(|_| range.seq)
+                })
+                .buffer_unordered(request.parallel);
+            while let Some(result) = downloads.next().await {
+                journal.ack(result?).await?;
+            }
+            progress.finish();
+        }
+
+        //4. 校验md5 (接口返回的md5可能是加密过的, 先还原)
+        if let Some(expect_md5) = file.md5.as_deref().filter(|_| request.verify) {
+            let expect_md5 = decode_server_md5(expect_md5);
+            let actual_md5 = md5_sum(part_path.to_str().unwrap()).await?;
+            if actual_md5 != expect_md5 {
+                //保留已经下载的数据和进度记录, 不用重新下载整个文件
+                return Err(YunPanError::Biz(format!(
+                    "md5 not match for {:?}, local:{} remote:{}. The downloaded data is kept in {:?}, \
+                    run again with --no-verify to accept it, or delete it to download again",
+                    local_path, actual_md5, expect_md5, part_path)));
+            }
+        }
+
+        tokio::fs::rename(&part_path, &local_path).await?;
+        journal.remove().await?;
+        Ok(CliDownloadResponse {
+            remote_path: file.path,
+            local_path,
+            size: file.size,
+            md5: file.md5.as_deref().map(decode_server_md5),
+        })
+    }
+
+    async fn get_quota(&self) -> Result<XPanQuotaResponse, YunPanError> {
+        let mut url = self.api_url(QUOTA_URL, None).await?;
+        url.query_pairs_mut()
+            .append_pair("checkfree", "1")
+            .append_pair("checkexpire", "1");
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = read_response(response, "quota", &[]).await?;
+
+        parse_response::<XPanQuotaResponse>(&raw_response_text, "quota")
+    }
+
+    pub async fn quota(&self) -> Result<XPanQuotaResponse, YunPanError> {
+        self.with_retry(|| self.get_quota()).await
+    }
+
+    async fn get_user_info(&self) -> Result<XPanUserInfoResponse, YunPanError> {
+        let url = self.api_url(XPAN_NAS_URL, Some("uinfo")).await?;
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = read_response(response, "uinfo", &[]).await?;
+
+        parse_response::<XPanUserInfoResponse>(&raw_response_text, "uinfo")
+    }
+
+    pub async fn user_info(&self) -> Result<XPanUserInfoResponse, YunPanError> {
+        self.with_retry(|| self.get_user_info()).await
+    }
+
+    /**
+     * 上传前检查剩余空间, 避免上传到一半才返回-10(容量不足)
+     * 查询容量失败时不影响上传
+     */
+    async fn check_free_space(&self, file_size: u64) -> Result<(), YunPanError> {
+        match self.quota().await {
+            Ok(quota) if quota.available() < file_size => Err(YunPanError::QuotaExceeded(format!(
+                "not enough space: need {}, only {} free", human_size(file_size), human_size(quota.available())))),
+            Ok(_) => Ok(()),
+            Err(YunPanError::AuthExpired(e)) => Err(YunPanError::AuthExpired(e)),
+            Err(e) => {
+                log::warn!("failed to check free space, uploading anyway: {}", e);
+                Ok(())
+            },
+        }
+    }
+
+    //文件管理 doc: https://pan.baidu.com/union/doc/mksg0s9l4
+    async fn filemanager(&self, operation: FileOperation, filelist: &str, ondup: &str, async_mode: u8) -> Result<XPanFileManagerResponse, YunPanError> {
+        let mut url = self.api_url(XPAN_FILE_URL, Some("filemanager")).await?;
+        url.query_pairs_mut()
+            .append_pair("opera", operation.as_param());
+        let form = [
+            ("async", async_mode.to_string()),
+            ("filelist", filelist.to_string()),
+            ("ondup", ondup.to_string()),
+        ];
+
+        let response = self.client.post(url).form(&form).send().await?;
+        //errno=12 批量操作部分失败, 每个文件的结果在info中
+        let raw_response_text = read_response(response, operation.as_param(), &[12]).await?;
+
+        parse_response::<XPanFileManagerResponse>(&raw_response_text, "filemanager")
+    }
+
+    //查询异步任务的状态
+    async fn task_query(&self, taskid: u64) -> Result<XPanTaskQueryResponse, YunPanError> {
+        let mut url = self.api_url(TASK_QUERY_URL, None).await?;
+        url.query_pairs_mut()
+            .append_pair("taskid", &taskid.to_string());
+
+        let response = self.client.get(url).send().await?;
+        let raw_response_text = read_response(response, &format!("taskquery {}", taskid), &[]).await?;
+
+        parse_response::<XPanTaskQueryResponse>(&raw_response_text, "taskquery")
+    }
+
+    /**
+     * 执行文件管理操作, paths为每一项对应的源路径(用于汇总结果)
+     * force_async为true时强制异步执行(async=2), 否则由服务端决定(async=1); 返回taskid时轮询直到任务结束
+     */
+    async fn manage_files(
+        &self,
+        operation: FileOperation,
+        paths: Vec<String>,
+        filelist: serde_json::Value,
+        on_conflict: ConflictPolicy,
+        force_async: bool,
+    ) -> Result<CliFileManagerResponse, YunPanError> {
+        let filelist = filelist.to_string();
+        let async_mode = if force_async { 2 } else { 1 };
+        //复制(newcopy)/移动/删除重复执行的结果都不一样
+        let response = self.with_retry_non_idempotent(|| self.filemanager(operation, &filelist, on_conflict.ondup(), async_mode)).await?;
+
+        let mut result = CliFileManagerResponse {
+            operation: operation.as_param(),
+            taskid: None,
+            succeeded: Vec::new(),
+            failed: Vec::new(),
+        };
+        if response.taskid == 0 {
+            //同步执行 每个文件的结果都在info中
+            for info in response.info {
+                if info.errno == 0 {
+                    result.succeeded.push(info.path);
+                } else {
+                    result.failed.push(CliFileManagerFailure { path: info.path, errno: info.errno });
+                }
+            }
+            return Ok(result);
+        }
+
+        //异步执行 任务只有整体的状态
+        log::info!("{} is running as task {}", operation.as_param(), response.taskid);
+        result.taskid = Some(response.taskid);
+        let deadline = Instant::now() + TASK_TIMEOUT;
+        let mut unknown_polls = 0;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(YunPanError::Biz(format!("task {} did not finish in {:?}, it may still be running", response.taskid, TASK_TIMEOUT)));
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let task = self.with_retry(|| self.task_query(response.taskid)).await?;
+            match task.status.as_str() {
+                "success" => {
+                    result.succeeded = paths;
+                    return Ok(result);
+                },
+                "failed" => {
+                    result.failed = paths.into_iter()
+                        .map(|path| CliFileManagerFailure { path, errno: task.task_errno })
+                        .collect();
+                    return Ok(result);
+                },
+                status @ ("pending" | "running") => {
+                    unknown_polls = 0;
+                    log::debug!("task {} is {}", response.taskid, status);
+                },
+                //没有status或者不认识的状态, 连续几次之后放弃
+                status => {
+                    unknown_polls += 1;
+                    if unknown_polls >= TASK_MAX_UNKNOWN_POLLS {
+                        return Err(YunPanError::Biz(format!("task {} returned unknown status {:?}", response.taskid, status)));
+                    }
+                    log::warn!("task {} returned unknown status {:?}", response.taskid, status);
+                },
+            }
+        }
+    }
+
+    fn file_name(path: &str) -> Result<String, YunPanError> {
+        Path::new(path).file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_string())
+            .ok_or_else(|| YunPanError::InvalidPath(format!("invalid remote path: {}", path)))
+    }
+
+    /**
+     * 复制/移动到dest_dir下, 文件名不变
+     */
+    async fn copy_or_move(&self, operation: FileOperation, sources: &[String], dest_dir: &str, on_conflict: ConflictPolicy, force_async: bool) -> Result<CliFileManagerResponse, YunPanError> {
+        let dest_dir = self.remote_path(dest_dir)?;
+        let mut paths = Vec::with_capacity(sources.len());
+        let mut filelist = Vec::with_capacity(sources.len());
+        for source in sources {
+            let path = self.remote_path(source)?;
+            filelist.push(serde_json::json!({
+                "path": path,
+                "dest": dest_dir,
+                "newname": Self::file_name(&path)?,
+                "ondup": on_conflict.ondup(),
+            }));
+            paths.push(path);
+        }
+        self.manage_files(operation, paths, serde_json::Value::Array(filelist), on_conflict, force_async).await
+    }
+
+    pub async fn copy_files(&self, sources: &[String], dest_dir: &str, on_conflict: ConflictPolicy, force_async: bool) -> Result<CliFileManagerResponse, YunPanError> {
+        self.copy_or_move(FileOperation::Copy, sources, dest_dir, on_conflict, force_async).await
+    }
+
+    pub async fn move_files(&self, sources: &[String], dest_dir: &str, on_conflict: ConflictPolicy, force_async: bool) -> Result<CliFileManagerResponse, YunPanError> {
+        self.copy_or_move(FileOperation::Move, sources, dest_dir, on_conflict, force_async).await
+    }
+
+    /**
+     * 重命名 new_name只是文件名, 不能包含 /
+     */
+    pub async fn rename(&self, path: &str, new_name: &str, on_conflict: ConflictPolicy, force_async: bool) -> Result<CliFileManagerResponse, YunPanError> {
+        if new_name.is_empty() || new_name.contains('/') || new_name == "." || new_name == ".." {
+            return Err(YunPanError::InvalidArgument(format!("invalid new name: {}", new_name)));
+        }
+        let path = self.remote_path(path)?;
+        let filelist = serde_json::json!([{ "path": path, "newname": new_name }]);
+        self.manage_files(FileOperation::Rename, vec![path], filelist, on_conflict, force_async).await
+    }
+
+    pub async fn delete(&self, paths: &[String], force_async: bool) -> Result<CliFileManagerResponse, YunPanError> {
+        let paths = paths.iter().map(|path| self.remote_path(path)).collect::<Result<Vec<_>, _>>()?;
+        //删除应用目录会把整个应用的数据都删掉, 不允许
+        if let Some(path) = paths.iter().find(|path| **path == self.app_root || **path == "/") {
+            return Err(YunPanError::Biz(format!("refuse to delete {}", path)));
+        }
+        let filelist = serde_json::json!(paths);
+        self.manage_files(FileOperation::Delete, paths, filelist, ConflictPolicy::Fail, force_async).await
+    }
+
+    /**
+     * 创建远程目录
+     * parents为true时同时创建不存在的上级目录, 目录已存在也不报错(同mkdir -p);
+     * 否则上级目录必须存在, 目录已存在时报错
+     */
+    pub async fn mkdir(&self, path: &str, parents: bool) -> Result<CliMkdirResponse, YunPanError> {
+        let path = self.remote_path(path)?;
+        if path == "/" {
+            return Err(YunPanError::Biz("cannot create /".to_string()));
+        }
+
+        let mut response = CliMkdirResponse { path: path.clone(), created: Vec::new() };
+        if parents {
+            //应用目录的上级目录(如 /apps)没有权限创建, 跳过
+            let components: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+            for i in 1..=components.len() {
+                let dir = format!("/{}", components[..i].join("/"));
+                if self.app_root.starts_with(&format!("{}/", dir)) {
+                    continue;
+                }
+                match self.with_retry(|| self.create_dir(&dir)).await? {
+                    Some(created) => {
+                        log::info!("created remote dir {}", dir);
+                        response.created.push(created);
+                    },
+                    None => log::debug!("remote dir {} already exists", dir),
+                }
+            }
+            return Ok(response);
+        }
+
+        //create接口会自动创建上级目录, 所以先检查上级目录是否存在
+        let parent = Path::new(&path).parent().and_then(|p| p.to_str()).unwrap_or("/");
+        if parent != "/" {
+            let parent_dir = match self.find_file(parent).await {
+                Ok(parent_dir) => parent_dir,
+                Err(YunPanError::NotFound(_)) => {
+                    return Err(YunPanError::NotFound(format!("{} does not exist, use -p to create parent directories", parent)));
+                },
+                Err(e) => return Err(e),
+            };
+            if parent_dir.isdir != 1 {
+                return Err(YunPanError::Biz(format!("{} is not a directory", parent)));
+            }
+        }
+        match self.with_retry(|| self.create_dir(&path)).await? {
+            Some(created) => response.created.push(created),
+            None => return Err(YunPanError::FileExists(path.clone())),
+        }
+        Ok(response)
+    }
+
+    /**
+     * 上传目录: 按照本地的目录结构创建远程目录并上传其中所有的文件
+     * 单个文件失败不影响其他文件, 最后汇总成功和失败的文件
+     */
+    pub async fn upload_dir(&self, request: CliUploadRequest) -> Result<CliUploadSummary, YunPanError> {
+        let local_root = Path::new(&request.file_path);
+        let dir_name = local_root.canonicalize()?
+            .file_name().and_then(|n| n.to_str())
+            .map(|n| n.to_string())
+            .ok_or_else(|| YunPanError::Biz(format!("invalid directory: {}", request.file_path)))?;
+        //--remote-path指定的就是远程目录本身, --remote-dir则在其下创建同名目录
+        let remote_root = match (&request.remote_path, &request.dir) {
+            (Some(remote_path), _) => self.upload_path(remote_path)?,
+            (None, Some(dir)) => self.upload_path(&format!("{}/{}", dir, dir_name))?,
+            (None, None) => self.upload_path(&dir_name)?,
+        };
+
+        let LocalTree { dirs, files, errors } = walk_dir(local_root);
+
+        //整个目录的大小一次检查, 空间不够时一个文件都不上传 (读不到大小的文件在上传时会报错)
+        let total_size: u64 = files.iter()
+            .filter_map(|file| std::fs::metadata(local_root.join(file)).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        if total_size > 0 {
+            self.check_free_space(total_size).await?;
+        }
+
+        let mut summary = CliUploadSummary::default();
+        //读取失败的目录/文件记录为失败, 其他的照常上传
+        for (path, e) in errors {
+            let local_path = local_root.join(path).to_string_lossy().to_string();
+            log::error!("cannot read {}: {}", local_path, e);
+            summary.failed.push(CliUploadFailure { file_path: local_path, error: YunPanError::Io(e).to_string() });
+        }
+
+        //先创建目录(父目录在前), 空目录也要保留
+        for dir in std::iter::once(PathBuf::new()).chain(dirs) {
+            let Some(remote_dir) = remote_join(&remote_root, &dir) else {
+                //其下的文件也会因为同样的原因失败, 各自记录
+                summary.failed.push(Self::non_utf8_failure(&local_root.join(&dir)));
+                continue;
+            };
+            match self.with_retry(|| self.create_dir(&remote_dir)).await? {
+                Some(_) => log::info!("created remote dir {}", remote_dir),
+                None => log::debug!("remote dir {} already exists", remote_dir),
+            }
+        }
+
+        for (index, file) in files.iter().enumerate() {
+            let local_path = local_root.join(file);
+            let (Some(local_path), Some(remote_path)) = (local_path.to_str(), remote_join(&remote_root, file)) else {
+                summary.failed.push(Self::non_utf8_failure(&local_path));
+                continue;
+            };
+            let local_path = local_path.to_string();
+            log::info!("[{}/{}] uploading {}", index + 1, files.len(), local_path);
+            let file_request = CliUploadRequest {
+                file_path: local_path.clone(),
+                remote_path: Some(remote_path),
+                dir: None,
+                check_free_space: false,
+                ..request
+            };
+            match self.upload(file_request).await {
+                Ok(response) => summary.succeeded.push(response),
+                Err(e) => {
+                    log::error!("upload {} failed: {}", local_path, e);
+                    summary.failed.push(CliUploadFailure { file_path: local_path, error: e.to_string() });
+                },
+            }
+        }
+        Ok(summary)
+    }
+
+    /** 网盘的路径只能是UTF-8, 文件名不是UTF-8的文件没法上传 */
+    fn non_utf8_failure(local_path: &Path) -> CliUploadFailure {
+        let file_path = local_path.to_string_lossy().to_string();
+        log::error!("cannot upload {}: file name is not valid UTF-8", file_path);
+        CliUploadFailure { file_path, error: "file name is not valid UTF-8".to_string() }
+    }
+
+    pub async fn upload(&self, request: CliUploadRequest) -> Result<CliUploadResponse, YunPanError> {
+        let upload_file = UploadFile::new(&request.file_path).await?;
+        let file_size = upload_file.file_size;
+
+        let upload_file_path = match (&request.remote_path, &request.dir) {
+            (Some(remote_path), _) => self.upload_path(remote_path)?,
+            (None, Some(dir)) => self.upload_path(&format!("{}/{}", dir, upload_file.file_name))?,
+            (None, None) => self.upload_path(&upload_file.file_name)?,
+        };
+
+        //precreate/create/rapidupload 的rtype需要保持一致
+        let rtype = request.on_conflict.rtype();
+
+        if request.check_free_space && file_size > 0 {
+            self.check_free_space(file_size).await?;
+        }
+
+        //续传记录以源文件的绝对路径为准
+        let absolute_path = std::fs::canonicalize(&upload_file.file_path)?
+                                            .to_str().unwrap().to_string();
+        let journal = if request.continues {
+            UploadJournal::load(&absolute_path, &upload_file_path, file_size, upload_file.mtime, request.chunk_size).await
+        } else {
+            None
+        };
+
+        let (slice_files, hashes) = match &journal {
+            //文件没有变化, 直接使用记录中的md5 不用再计算一遍
+            Some(journal) => (slices_of(&upload_file.file_path, file_size,
+                                upload_file.slice_size(request.chunk_size), &journal.block_list), journal.hashes()),
+            None => {
+                //split(物理切割) vs split2(逻辑分割), 秒传需要的md5也在这一遍读取中算出来
+                let (mut slice_files, hashes) = upload_file.split2(request.chunk_size).await?;
+                //排序 保证下面的block_list得到的顺序是按照seq来的,但是发送(upload_slice)的顺序随意 保证 block_list的位置即可
+                slice_files.sort_by_key(|sf| sf.seq);
+                (slice_files, Some(hashes))
+            }
+        };
+        let hashes = match hashes {
+            Some(hashes) => hashes,
+            //旧版本的续传记录中没有整个文件的md5, 只能再读一遍
+            None => hash_file(&upload_file.file_path, upload_file.slice_size(request.chunk_size), RAPID_UPLOAD_SLICE_SIZE, 1).await?,
+        };
+
+        //0. 先尝试秒传, 失败了再走 预上传->分片上传->创建 的流程
+        if file_size > RAPID_UPLOAD_SLICE_SIZE {
+            let rapid_request = XPanRapidUploadRequest::new(&upload_file_path, file_size,
+                hashes.content_md5.clone(), hashes.slice_md5.clone(), rtype);
+            let rapid_upload = || self.rapid_upload(&rapid_request);
+            let result = if request.on_conflict.is_idempotent() {
+                self.with_retry(rapid_upload).await
+            } else {
+                self.with_retry_non_idempotent(rapid_upload).await
+            };
+            match result {
+                Ok(Some(info)) => {
+                    log::info!("rapidupload:: done without sending data: {}", info.path);
+                    if info.path != upload_file_path {
+                        log::warn!("{} already exists, uploaded as {}", upload_file_path, info.path);
+                    }
+                    return Ok(CliUploadResponse::from(info));
+                },
+                Ok(None) => log::info!("rapidupload:: no identical file in cloud, uploading"),
+                //重试之后还是临时性的错误时走普通上传, 其他错误(容量不足/已存在等)普通上传也一样会失败
+                Err(e) if e.is_retryable() => log::warn!("rapidupload:: failed, fallback to normal upload: {}", e),
+                Err(e) => return Err(e),
+            }
+        }
+   
+        let block_list: Vec<String> = slice_files.iter().map(|sf|  sf.md5.clone()).collect();
+
+        //1. 预上传
+        let mut pcreate_request  = 
+            XPanFilePreCreateRequest::new(&upload_file_path, file_size, &block_list, rtype);
+        pcreate_request.uploadid = journal.as_ref().map(|j| j.upload_id.clone());
+
+        let response =  self.with_retry(|| self.precreate(&pcreate_request)).await?;
+        if response.return_type == 2 {
+            //云端已经有这个文件了 不需要再上传, 此时没有uploadid, 不能继续走分片上传
+            let info = match response.info {
+                Some(info) => info,
+                //没有返回文件信息时按路径查找, 找不到就报错
+                None => self.find_file(&upload_file_path).await?,
+            };
+            log::info!("precreate:: file already exists: {}", info.path);
+            if let Some(journal) = journal {
+                journal.remove().await?;
+            }
+            return Ok(CliUploadResponse::from(info));
+        }
+        if response.upload_id.is_empty() {
+            return Err(YunPanError::Biz(format!("precreate returned no uploadid for {}", upload_file_path)));
+        }
+        let upload_id = response.upload_id.as_str();
+        log::info!("precreate::  upload_id:{}", upload_id );
+
+        //upload_id没变的话沿用之前已经上传的分片,否则(过期等)从头开始
+        let mut journal = match journal {
+            Some(journal) if journal.upload_id == upload_id => {
+                log::info!("resuming upload, {} of {} slices already uploaded", journal.uploaded.len(), block_list.len());
+                journal
+            },
+            _ => UploadJournal::new(&absolute_path, &upload_file_path, file_size,
+                    upload_file.mtime, request.chunk_size, &hashes, upload_id),
+        };
+
+        //只上传服务端要求的分片(服务端已经有的分片就不用再传了)
+        let required_seqs: hash_set::HashSet<u64> =
+            response.block_list.iter().map(|seq| *seq as u64).collect();
+        //以服务端为准: 记录中上传过但服务端还要求的分片需要重新上传, 否则create会一直失败
+        let stale_seqs: Vec<u64> = journal.uploaded.iter().copied().filter(|seq| required_seqs.contains(seq)).collect();
+        if !stale_seqs.is_empty() {
+            log::warn!("server asks for slices {:?} again, uploading them again", stale_seqs);
+            journal.uploaded.retain(|seq| !required_seqs.contains(seq));
+        }
+        journal.save().await?;
+
+        let slice_file_paths : hash_set::HashSet<String> = 
+            slice_files.iter()
+                .map(|sf| sf.file_path.to_string().clone())
+                .collect();
+
+
+        //2. 分片上传 最多同时上传parallel个分片, 完成顺序无所谓 create时的block_list已经按seq排好
+        let pending_slices: Vec<&SliceFileInfo> = slice_files.iter()
+            .filter(|sf| {
+                let pending = required_seqs.contains(&sf.seq);
+                if !pending {
+                    log::info!("skipping slice:{} (already uploaded)", sf.seq);
+                }
+                pending
+            })
+            .collect();
+
+        let pending_size: u64 = pending_slices.iter().map(|sf| sf.size).sum();
+        let progress = Progress::new(self.progress_mode, &upload_file.file_name, file_size, file_size - pending_size);
+        let progress_ref = &progress;
+        let upload_file_path_ref = upload_file_path.as_str();
+        let mut uploads = futures::stream::iter(pending_slices)
+            .map(|slice_file| async move {
+                log::debug!("uploading slice:{} md5:{}", slice_file.seq,slice_file.md5.as_str());
+                //upload_slice vs upload_slice2
+                let (upload_slice_response, retries) = self.with_retry_counted(true,
+                    || self.upload_slice2(upload_file_path_ref, upload_id, slice_file, progress_ref)).await?;
+                if upload_slice_response.md5 != slice_file.md5 {
+                    return Err(YunPanError::Biz(format!("md5 not match on seq:{}, local:{} remote:{}",
+                        slice_file.seq, slice_file.md5, upload_slice_response.md5)));
+                }
+                //注意 物理分割时 file_path为分片的路径, 逻辑分割时file_path为源文件路径!
+                Ok((slice_file.seq, retries))
+            })
+            .buffer_unordered(request.parallel);
+
+        let mut slice_retries = BTreeMap::new();
+        while let Some(result) = uploads.next().await {
+            let (seq, retries) = result?;
+            if retries > 0 {
+                slice_retries.insert(seq, retries);
+            }
+            journal.ack(seq).await?;
+        }
+        progress.finish();
+
+        // - 删除临时文件
+        if slice_file_paths.len() > 1 {// =1的时候证明没有切片,即为源文件/ 或者是逻辑切分的所以这里的slice_file也是源文件的路径  
+            log::info!("removing slice files: {:?}", slice_file_paths);
+            for slice_path in slice_file_paths.iter() {
+                tokio::fs::remove_file(&slice_path).await?;
+            }
+        }
+
+        //3. 创建文件
+        let create_request = XPanFileCreateRequest::new(
+            &upload_file_path,
             file_size,
             &block_list,
             upload_id,
+            rtype,
         );
 
-        self.create(&create_request).await
+        let create = || self.create(&create_request);
+        let create_response = if request.on_conflict.is_idempotent() {
+            self.with_retry(create).await?
+        } else {
+            self.with_retry_non_idempotent(create).await?
+        };
+        if create_response.path != upload_file_path {
+            log::warn!("{} already exists, uploaded as {}", upload_file_path, create_response.path);
+        }
+        //上传完成 续传记录就没用了
+        journal.remove().await?;
+        Ok(CliUploadResponse { file: create_response, slice_retries })
     }
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_api_errno_and_error_code_are_separate() {
+        let error = |errno: Option<i64>, error_code: Option<i64>| YunPanError::from_api(errno, error_code, String::new());
+        //errno (xpan接口)
+        assert!(matches!(error(Some(-6), None), YunPanError::AuthExpired(_)));
+        assert!(matches!(error(Some(-7), None), YunPanError::InvalidPath(_)));
+        assert!(matches!(error(Some(-8), None), YunPanError::FileExists(_)));
+        assert!(matches!(error(Some(31061), None), YunPanError::FileExists(_)));
+        assert!(matches!(error(Some(-9), None), YunPanError::NotFound(_)));
+        assert!(matches!(error(Some(31066), None), YunPanError::NotFound(_)));
+        assert!(matches!(error(Some(-10), None), YunPanError::QuotaExceeded(_)));
+        assert!(matches!(error(Some(31034), None), YunPanError::RateLimited(_)));
+        //error_code (网关层/pcs接口)
+        assert!(matches!(error(None, Some(110)), YunPanError::AuthExpired(_)));
+        assert!(matches!(error(None, Some(111)), YunPanError::AuthExpired(_)));
+        assert!(matches!(error(None, Some(4)), YunPanError::RateLimited(_)));
+        assert!(matches!(error(None, Some(17)), YunPanError::RateLimited(_)));
+        assert!(matches!(error(None, Some(18)), YunPanError::RateLimited(_)));
+        assert!(matches!(error(None, Some(31034)), YunPanError::RateLimited(_)));
+        assert!(matches!(error(None, Some(1)), YunPanError::ServerBusy(_)));
+        assert!(matches!(error(None, Some(2)), YunPanError::ServerBusy(_)));
+        //同一个数字在两种错误码中意思不一样
+        assert!(matches!(error(Some(111), None), YunPanError::Api { errno: 111, .. }));
+        assert!(matches!(error(Some(2), None), YunPanError::Api { errno: 2, .. }));
+        assert!(matches!(error(Some(4), None), YunPanError::Api { errno: 4, .. }));
+        assert!(matches!(error(None, Some(-9)), YunPanError::Api { errno: -9, .. }));
+        assert!(matches!(error(None, Some(31066)), YunPanError::Api { errno: 31066, .. }));
+        assert!(matches!(error(None, None), YunPanError::Biz(_)));
+    }
+
+    #[test]
+    fn check_response_decodes_errors() {
+        let ok = reqwest::StatusCode::OK;
+        assert!(check_response(ok, r#"{"errno":0,"list":[]}"#, "list", &[]).is_ok());
+        //没有errno字段的正常响应
+        assert!(check_response(ok, r#"{"list":[]}"#, "list", &[]).is_ok());
+        assert!(check_response(ok, r#"{"errno":12,"info":[]}"#, "copy", &[12]).is_ok());
+        assert!(matches!(check_response(ok, r#"{"errno":12,"info":[]}"#, "copy", &[]), Err(YunPanError::Api { errno: 12, .. })));
+        assert!(matches!(check_response(ok, r#"{"errno":-9}"#, "stat", &[]), Err(YunPanError::NotFound(_))));
+        assert!(matches!(check_response(ok, r#"{"error_code":111,"error_msg":"expired"}"#, "quota", &[]), Err(YunPanError::AuthExpired(_))));
+        //错误码优先于HTTP状态
+        let bad_request = reqwest::StatusCode::BAD_REQUEST;
+        assert!(matches!(check_response(bad_request, r#"{"errno":-10}"#, "create", &[]), Err(YunPanError::QuotaExceeded(_))));
+        assert!(matches!(check_response(reqwest::StatusCode::BAD_GATEWAY, "<html>", "list", &[]), Err(YunPanError::ServerError(_))));
+    }
+
+    #[test]
+    fn from_status_only_503_is_unprocessed() {
+        let error = |status: u16| YunPanError::from_status(reqwest::StatusCode::from_u16(status).unwrap(), String::new());
+        assert!(matches!(error(503), YunPanError::ServerBusy(_)));
+        assert!(matches!(error(502), YunPanError::ServerError(_)));
+        assert!(matches!(error(429), YunPanError::RateLimited(_)));
+        assert!(matches!(error(401), YunPanError::AuthExpired(_)));
+        //500/502/504时服务端可能已经处理过, 只有幂等的请求可以重试
+        assert!(error(500).is_retryable() && !error(500).is_retryable_unprocessed());
+        assert!(error(503).is_retryable_unprocessed());
+        assert!(error(429).is_retryable_unprocessed());
+        assert!(!error(404).is_retryable());
+    }
+
+    #[test]
+    fn invalid_app_root_is_an_error() {
+        for app_root in ["apps/test", "/apps/../test", ""] {
+            let options = ServiceOptions { app_root: Some(app_root.to_string()), ..Default::default() };
+            let result = YunPanService::new(TokenProvider::fixed("token".to_string()), options);
+            assert!(matches!(result, Err(YunPanError::InvalidPath(_))), "app root {:?}", app_root);
+        }
+        let options = ServiceOptions { app_root: Some("/apps/test/".to_string()), ..Default::default() };
+        assert_eq!(YunPanService::new(TokenProvider::fixed("token".to_string()), options).unwrap().app_root, "/apps/test");
+    }
+
+    #[tokio::test]
+    async fn split2_empty_file() {
+        let path = std::env::temp_dir().join(format!("baidu_yunpan_test_{}_empty_upload", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let upload_file = UploadFile::new(path.to_str().unwrap()).await.unwrap();
+        let (slices, hashes) = upload_file.split2(4 * 1024 * 1024).await.unwrap();
+        assert_eq!(slices.len(), 1);
+        assert_eq!(slices[0].size, 0);
+        assert_eq!(hashes.block_list, vec!["d41d8cd98f00b204e9800998ecf8427e".to_string()]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}